@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::path::PathBuf;
 use tracing::info;
+use tracing::warn;
 
 //------------------------------------------------------------------------------
 
@@ -12,12 +17,81 @@ pub struct IPEndpointConfig {
     pub addr: Option<String>,
 
     /// Port of the endpoint
+    ///
+    /// For broker listeners, 0 binds on a free port chosen by the system. The
+    /// resolved port is reported by the broker handle.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub port: Option<u16>,
 }
 
 // ============================================================================
 
+impl From<SocketAddr> for IPEndpointConfig {
+    /// Endpoint matching a socket address, like the one of a running broker
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            addr: Some(addr.ip().to_string()),
+            port: Some(addr.port()),
+        }
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Configuration for a TLS endpoint
+pub struct TlsEndpointConfig {
+    /// Bind/Connect address of the endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+
+    /// Port of the endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// Path to the PEM certificate chain, relative to the user root directory
+    /// if not absolute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert_path: Option<String>,
+
+    /// Path to the PEM private key, relative to the user root directory if not
+    /// absolute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>,
+}
+
+// ============================================================================
+
+impl TlsEndpointConfig {
+    /// IP part of the endpoint
+    pub fn ip_endpoint(&self) -> IPEndpointConfig {
+        IPEndpointConfig {
+            addr: self.addr.clone(),
+            port: self.port,
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    /// Certificate path resolved against the user root directory
+    pub fn resolved_cert_path(&self) -> Option<PathBuf> {
+        self.cert_path
+            .as_ref()
+            .and_then(crate::path::resolve_user_path)
+    }
+
+    // ------------------------------------------------------------------------
+
+    /// Private key path resolved against the user root directory
+    pub fn resolved_key_path(&self) -> Option<PathBuf> {
+        self.key_path
+            .as_ref()
+            .and_then(crate::path::resolve_user_path)
+    }
+}
+
+// ============================================================================
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// Configuration for a USB endpoint
 pub struct UsbEndpointConfig {
@@ -54,8 +128,468 @@ pub struct SerialPortEndpointConfig {
 
 // ============================================================================
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Tuning of the broker router
+///
+/// Missing values fall back to the toolkit defaults.
+pub struct MqttRouterConfig {
+    /// Maximum number of connections handled by the router
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of packets buffered for a connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_outgoing_packet_count: Option<u64>,
+
+    /// Size in bytes of a segment of the commit log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_segment_size: Option<usize>,
+
+    /// Number of segments kept in memory per topic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_segment_count: Option<usize>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Limits applied to the connections of the broker listeners
+///
+/// Missing values fall back to the toolkit defaults of each listener kind.
+pub struct MqttConnectionLimitsConfig {
+    /// Time given to a client to send its CONNECT packet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_timeout_ms: Option<u16>,
+
+    /// Maximum size in bytes of a packet payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_payload_size: Option<usize>,
+
+    /// Maximum number of inflight QoS 1 and 2 messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_inflight_count: Option<usize>,
+
+    /// Maximum length of a client ID (WebSocket only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_client_id_len: Option<usize>,
+
+    /// Delay between two packets of a connection (WebSocket only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttle_delay_ms: Option<u64>,
+
+    /// Maximum size in bytes of the inflight messages (WebSocket only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_inflight_size: Option<usize>,
+
+    /// True to create topics on the fly when clients subscribe to them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamic_filters: Option<bool>,
+
+    /// Delay between two accepted connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_connection_delay_ms: Option<u64>,
+}
+
+// ============================================================================
+
+impl MqttConnectionLimitsConfig {
+    /// Complete these limits with other ones, field by field
+    pub fn or(&self, other: &MqttConnectionLimitsConfig) -> Self {
+        Self {
+            connection_timeout_ms: self.connection_timeout_ms.or(other.connection_timeout_ms),
+            max_payload_size: self.max_payload_size.or(other.max_payload_size),
+            max_inflight_count: self.max_inflight_count.or(other.max_inflight_count),
+            max_client_id_len: self.max_client_id_len.or(other.max_client_id_len),
+            throttle_delay_ms: self.throttle_delay_ms.or(other.throttle_delay_ms),
+            max_inflight_size: self.max_inflight_size.or(other.max_inflight_size),
+            dynamic_filters: self.dynamic_filters.or(other.dynamic_filters),
+            next_connection_delay_ms: self
+                .next_connection_delay_ms
+                .or(other.next_connection_delay_ms),
+        }
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// Protocol of a named broker listener
+pub enum MqttListenerProtocol {
+    /// MQTT over TCP
+    Tcp,
+    /// MQTT over WebSocket
+    Websocket,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Named listener of the built-in broker
+///
+/// ```json
+/// { "name": "lab", "protocol": "tcp", "addr": "192.168.1.10", "port": 1883 }
+/// ```
+pub struct MqttListenerConfig {
+    /// Name of the listener, unique in the broker
+    pub name: String,
+
+    /// Protocol of the listener
+    pub protocol: MqttListenerProtocol,
+
+    /// Address and port of the listener
+    #[serde(flatten)]
+    pub endpoint: IPEndpointConfig,
+
+    /// Connection limits of the listener, missing values are the ones of the
+    /// broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connections: Option<MqttConnectionLimitsConfig>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Authentication of the built-in broker
+///
+/// When present, only the listed users can connect to the broker.
+pub struct MqttAuthConfig {
+    /// Users allowed to connect (default: every user of the password file)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<Vec<String>>,
+
+    /// File holding the password hashes of the users, relative to the user
+    /// root directory if not absolute (default: `broker-passwords.json5`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
+}
+
+// ============================================================================
+
+impl MqttAuthConfig {
+    /// Password file resolved against the user root directory
+    pub fn resolved_password_file(&self) -> Option<PathBuf> {
+        crate::path::resolve_user_path(
+            self.password_file
+                .as_deref()
+                .unwrap_or("broker-passwords.json5"),
+        )
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Credentials used by a client to connect to a broker
+pub struct MqttCredentialsConfig {
+    /// User name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Password
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Permission granted by an ACL rule
+pub enum MqttAclPermission {
+    /// The operation is accepted
+    Allow,
+    /// The operation is rejected
+    Deny,
+}
+
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Operation controlled by an ACL rule
+pub enum MqttAclAction {
+    /// Publish on a topic
+    Publish,
+    /// Subscribe to a topic filter
+    Subscribe,
+    /// Both publish and subscribe
+    All,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// ACL rule of the built-in broker
+///
+/// ```json5
+/// // read-only dashboard
+/// { users: ["dashboard"], prefix: "pza", action: "subscribe", permission: "allow", topics: ["#"] },
+/// { users: ["dashboard"], action: "all", permission: "deny", topics: ["#"] },
+/// ```
+pub struct MqttAclRuleConfig {
+    /// Users the rule applies to, all the clients if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<Vec<String>>,
+
+    /// Client ID prefixes the rule applies to (the module names given to
+    /// `init_client`), all the clients if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ids: Option<Vec<String>>,
+
+    /// Prefix prepended to the topics, like `topic_with_prefix` does on the
+    /// client side
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+
+    /// Operation controlled by the rule
+    pub action: MqttAclAction,
+
+    /// Permission granted by the rule
+    pub permission: MqttAclPermission,
+
+    /// Topic filters of the rule, `#` may be used at any level
+    pub topics: Vec<String>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Topic level ACLs of the built-in broker
+///
+/// Rules are evaluated in order and the first matching one decides. They are
+/// enforced on the plain tcp listeners and on the local clients, which are
+/// identified by the client ids "local-clients-<n>". The loopback listeners of the
+/// broker core behind them only accept the relay. Without `auth`, client ids
+/// and user names are chosen by the clients themselves.
+pub struct MqttAclConfig {
+    /// Permission when no rule matches (default: allow)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_permission: Option<MqttAclPermission>,
+
+    /// Rules of the ACL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rules: Option<Vec<MqttAclRuleConfig>>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Statistics of the built-in broker
+///
+/// Clients, subscriptions and message counters only cover the plain tcp
+/// listeners, which are then inspected. The other listeners only report their
+/// connections and bytes. The totals of the broker core cover every listener
+/// and the local clients.
+pub struct MqttStatsConfig {
+    /// Interval between two publications of the statistics, in seconds. They
+    /// are not published if missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sys_interval_secs: Option<u64>,
+
+    /// Topic prefix of the published statistics (default: "$SYS/broker")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sys_prefix: Option<String>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Persistence of the retained messages of the built-in broker
+///
+/// Every retained message published to the broker is stored, then published
+/// again when the broker starts. Messages of the network clients are stored
+/// with a QoS of at most 1, the ones of the local clients keep their QoS.
+pub struct MqttRetainedStoreConfig {
+    /// File of the store, relative to the user root directory if not absolute
+    /// (default: "broker-retained.json")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+
+    /// Maximum total size of the stored payloads in bytes (default: 10 MiB)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+}
+
+// ============================================================================
+
+impl MqttRetainedStoreConfig {
+    /// Store file resolved against the user root directory
+    pub fn resolved_file(&self) -> Option<PathBuf> {
+        crate::path::resolve_user_path(self.file.as_deref().unwrap_or("broker-retained.json"))
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Recording of the messages published through the built-in broker
+///
+/// Every message published by a local client or through a plain tcp listener
+/// is captured, one JSON line each, with the client id of its publisher and
+/// its own QoS. The messages of the websocket and TLS listeners cannot be read
+/// by the broker and are not recorded.
+pub struct MqttRecorderConfig {
+    /// Capture file, relative to the user root directory if not absolute
+    /// (default: "broker-capture.jsonl")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+
+    /// Size in bytes from which the capture file is rotated (default: 10 MiB)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+
+    /// Number of rotated files kept next to the current one (default: 5)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+}
+
+// ============================================================================
+
+impl MqttRecorderConfig {
+    /// Capture file resolved against the user root directory
+    pub fn resolved_file(&self) -> Option<PathBuf> {
+        crate::path::resolve_user_path(self.file.as_deref().unwrap_or("broker-capture.jsonl"))
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// Direction of the messages of a bridge topic
+pub enum MqttBridgeDirection {
+    /// From the built-in broker to the remote one
+    Out,
+    /// From the remote broker to the built-in one
+    In,
+    /// Both ways
+    Both,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Topics forwarded by a bridge
+///
+/// With prefixes, `measure/#` bridges `{local_prefix}/measure/#` on the
+/// built-in broker with `{remote_prefix}/measure/#` on the remote one.
+pub struct MqttBridgeTopicConfig {
+    /// Topic filter, relative to the prefixes
+    pub filter: String,
+
+    /// Direction of the messages
+    pub direction: MqttBridgeDirection,
+
+    /// Prefix of the topics on the built-in broker (default: none)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_prefix: Option<String>,
+
+    /// Prefix of the topics on the remote broker (default: none)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_prefix: Option<String>,
+}
+
+// ============================================================================
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
+/// Bridge between the built-in broker and a remote one
+pub struct MqttBridgeConfig {
+    /// Endpoint of the remote broker
+    pub remote: IPEndpointConfig,
+
+    /// Credentials used to connect to the remote broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<MqttCredentialsConfig>,
+
+    /// Name of the bridge, prefix of its client id (default: "bridge")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Forwarded topics
+    pub topics: Vec<MqttBridgeTopicConfig>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Options of the MQTT clients connecting to the broker
+pub struct MqttClientConfig {
+    /// Keep-alive interval in seconds (default: 3)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive_secs: Option<u64>,
+
+    /// Capacity of the request channel between the client and its event loop
+    /// (default: 100)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<usize>,
+
+    /// True to start a new session on each connection (default: true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean_session: Option<bool>,
+
+    /// Queue of the messages published while the broker is unreachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_queue: Option<MqttOfflineQueueConfig>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// What to do with a new message when the offline queue is full
+pub enum MqttQueueDropPolicy {
+    /// Drop the oldest messages to make room for the new one
+    #[default]
+    DropOldest,
+    /// Reject the new message
+    RejectNew,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Configuration of the offline publish queue
+pub struct MqttOfflineQueueConfig {
+    /// Maximum number of queued messages (default: 10000)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+
+    /// Maximum total size of the queued payloads in bytes (default: 10 MiB)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+
+    /// Messages older than this are dropped, in seconds (default: no limit)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+
+    /// Behaviour when the queue is full (default: drop_oldest)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drop_policy: Option<MqttQueueDropPolicy>,
+
+    /// File the queue is persisted to, relative to the user root directory if
+    /// not absolute. The queue is only kept in memory if missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+// ============================================================================
+
+impl MqttOfflineQueueConfig {
+    /// Queue file resolved against the user root directory
+    pub fn resolved_file(&self) -> Option<PathBuf> {
+        self.file.as_ref().and_then(crate::path::resolve_user_path)
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 /// Configuration for a broker
+///
+/// New sections are added over time: start from [`Default`] or from one of
+/// the constructors, then set the fields.
 pub struct MqttBrokerConfig {
     /// True to use the built-in broker, false to use an external one
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -68,6 +602,192 @@ pub struct MqttBrokerConfig {
     /// WebSocket endpoint configuration
     #[serde(skip_serializing_if = "Option::is_none")]
     pub websocket: Option<IPEndpointConfig>,
+
+    /// MQTT over TLS endpoint configuration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsEndpointConfig>,
+
+    /// WebSocket over TLS endpoint configuration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wss: Option<TlsEndpointConfig>,
+
+    /// TCP endpoint configuration for MQTT v5 clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_v5: Option<IPEndpointConfig>,
+
+    /// Additional named listeners, each with its own limits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listeners: Option<Vec<MqttListenerConfig>>,
+
+    /// True if the built-in broker only serves in-process clients, without any
+    /// listener (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_only: Option<bool>,
+
+    /// Router tuning of the built-in broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub router: Option<MqttRouterConfig>,
+
+    /// Connection limits of the built-in broker listeners
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connections: Option<MqttConnectionLimitsConfig>,
+
+    /// Authentication of the built-in broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<MqttAuthConfig>,
+
+    /// Topic level ACLs of the built-in broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acl: Option<MqttAclConfig>,
+
+    /// Statistics of the built-in broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<MqttStatsConfig>,
+
+    /// Bridge of the built-in broker to a remote one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<MqttBridgeConfig>,
+
+    /// Persistence of the retained messages of the built-in broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retained_store: Option<MqttRetainedStoreConfig>,
+
+    /// Recording of the published messages into a capture file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recorder: Option<MqttRecorderConfig>,
+
+    /// Credentials used by the clients to connect to the broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<MqttCredentialsConfig>,
+
+    /// Options of the clients connecting to the broker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<MqttClientConfig>,
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+/// Problem found in a broker configuration
+pub enum BrokerConfigProblem {
+    /// An endpoint has no address
+    #[error("{endpoint}: missing 'addr'")]
+    MissingAddr {
+        /// Name of the endpoint section
+        endpoint: String,
+    },
+
+    /// An endpoint has no port
+    #[error("{endpoint}: missing 'port'")]
+    MissingPort {
+        /// Name of the endpoint section
+        endpoint: String,
+    },
+
+    /// The address of a listener is not an IP address
+    #[error("{endpoint}: '{addr}' is not an IP address")]
+    InvalidAddr {
+        /// Name of the endpoint section
+        endpoint: String,
+        /// Configured address
+        addr: String,
+    },
+
+    /// Two listeners would be bound on the same address
+    #[error("{first} and {second} both listen on port {port}")]
+    PortConflict {
+        /// Name of the first endpoint section
+        first: String,
+        /// Name of the second endpoint section
+        second: String,
+        /// Shared port
+        port: u16,
+    },
+
+    /// A listener port may need administrator privileges, only a warning
+    #[error("{endpoint}: port {port} is privileged, binding it may need administrator rights")]
+    PrivilegedPort {
+        /// Name of the endpoint section
+        endpoint: String,
+        /// Configured port
+        port: u16,
+    },
+
+    /// A TLS endpoint has no certificate or no private key
+    #[error("{endpoint}: missing '{field}'")]
+    MissingTlsFile {
+        /// Name of the endpoint section
+        endpoint: String,
+        /// Name of the missing field
+        field: String,
+    },
+
+    /// ACLs are configured with a listener they cannot be enforced on
+    #[error("{endpoint}: ACLs cannot be enforced on this listener, only on plain tcp ones")]
+    AclNotEnforced {
+        /// Name of the endpoint in the configuration
+        endpoint: String,
+    },
+
+    /// The recorder is configured with a listener it cannot read, only a
+    /// warning
+    #[error(
+        "{endpoint}: messages of this listener are not recorded, only those of plain tcp ones"
+    )]
+    NotRecorded {
+        /// Name of the endpoint in the configuration
+        endpoint: String,
+    },
+
+    /// No listener is configured for a broker not marked as local only
+    #[error("no listener configured, set 'local_only' if only in-process clients use the broker")]
+    NoListener,
+
+    /// Two named listeners have the same name
+    #[error("listeners: name '{name}' used more than once")]
+    DuplicateListener {
+        /// Name of the listeners
+        name: String,
+    },
+
+    /// A topic filter is not a valid MQTT filter
+    #[error("{section}: invalid topic filter '{filter}'")]
+    InvalidFilter {
+        /// Name of the section
+        section: String,
+        /// Configured filter
+        filter: String,
+    },
+}
+
+// ----------------------------------------------------------------------------
+
+impl BrokerConfigProblem {
+    /// True if the problem does not prevent the broker from starting
+    pub fn is_warning(&self) -> bool {
+        matches!(self, Self::PrivilegedPort { .. } | Self::NotRecorded { .. })
+    }
+}
+
+// ============================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("invalid broker configuration: {}", display_problems(.problems))]
+/// Error of a broker configuration, listing all its problems
+pub struct BrokerConfigError {
+    /// Problems of the configuration, in the order of its sections
+    pub problems: Vec<BrokerConfigProblem>,
+}
+
+// ----------------------------------------------------------------------------
+
+/// Problems joined into one line
+fn display_problems(problems: &[BrokerConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| problem.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
 }
 
 // ============================================================================
@@ -85,8 +805,259 @@ impl MqttBrokerConfig {
                 addr: Some("0.0.0.0".into()),
                 port: Some(8083),
             }),
+            tls: None,
+            wss: None,
+            tcp_v5: None,
+            listeners: None,
+            local_only: None,
+            router: None,
+            connections: None,
+            auth: None,
+            acl: None,
+            stats: None,
+            bridge: None,
+            retained_store: None,
+            recorder: None,
+            credentials: None,
+            client: None,
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    /// Create a new MqttBrokerConfig listening on a free loopback port
+    ///
+    /// Useful for tests running many isolated brokers in parallel.
+    pub fn new_ephemeral() -> Self {
+        Self {
+            use_builtin: Some(true),
+            tcp: Some(IPEndpointConfig {
+                addr: Some("127.0.0.1".into()),
+                port: Some(0),
+            }),
+            websocket: None,
+            tls: None,
+            wss: None,
+            tcp_v5: None,
+            listeners: None,
+            local_only: None,
+            router: None,
+            connections: None,
+            auth: None,
+            acl: None,
+            stats: None,
+            bridge: None,
+            retained_store: None,
+            recorder: None,
+            credentials: None,
+            client: None,
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    /// Check the configuration of the built-in broker
+    ///
+    /// Reports every error at once, see [`MqttBrokerConfig::problems`]. The
+    /// warnings are only logged.
+    pub fn validate(&self) -> Result<(), BrokerConfigError> {
+        let (warnings, problems): (Vec<_>, Vec<_>) = self
+            .problems()
+            .into_iter()
+            .partition(BrokerConfigProblem::is_warning);
+        for warning in warnings {
+            warn!("Broker configuration: {}", warning);
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(BrokerConfigError { problems }),
         }
     }
+
+    // ------------------------------------------------------------------------
+
+    /// Listeners whose packets the relay cannot read, websocket and TLS ones
+    fn uninspected_listeners(&self) -> Vec<String> {
+        let single = [
+            ("websocket", self.websocket.is_some()),
+            ("tls", self.tls.is_some()),
+            ("wss", self.wss.is_some()),
+        ];
+        let single = single
+            .iter()
+            .filter(|(_, configured)| *configured)
+            .map(|(name, _)| name.to_string());
+        let named = self
+            .listeners
+            .iter()
+            .flatten()
+            .filter(|listener| listener.protocol == MqttListenerProtocol::Websocket)
+            .map(|listener| format!("listeners.{}", listener.name));
+        single.chain(named).collect()
+    }
+
+    // ------------------------------------------------------------------------
+
+    /// Every problem of the configuration of the built-in broker, warnings
+    /// included
+    ///
+    /// Missing fields, addresses which are not IP addresses, listeners sharing
+    /// a port or a name, privileged ports, no listener without `local_only`,
+    /// ACLs or a recorder with listeners they cannot apply to and invalid
+    /// bridge topic filters.
+    pub fn problems(&self) -> Vec<BrokerConfigProblem> {
+        let mut problems = Vec::new();
+
+        // Listeners, with their names in the configuration
+        let mut listeners: Vec<(String, IPEndpointConfig)> = Vec::new();
+        if let Some(tcp) = &self.tcp {
+            listeners.push(("tcp".to_string(), tcp.clone()));
+        }
+        if let Some(websocket) = &self.websocket {
+            listeners.push(("websocket".to_string(), websocket.clone()));
+        }
+        if let Some(tls) = &self.tls {
+            listeners.push(("tls".to_string(), tls.ip_endpoint()));
+        }
+        if let Some(wss) = &self.wss {
+            listeners.push(("wss".to_string(), wss.ip_endpoint()));
+        }
+        if let Some(tcp_v5) = &self.tcp_v5 {
+            listeners.push(("tcp_v5".to_string(), tcp_v5.clone()));
+        }
+        let mut names: Vec<&str> = Vec::new();
+        for listener in self.listeners.iter().flatten() {
+            if names.contains(&listener.name.as_str()) {
+                problems.push(BrokerConfigProblem::DuplicateListener {
+                    name: listener.name.clone(),
+                });
+            }
+            names.push(&listener.name);
+            listeners.push((
+                format!("listeners.{}", listener.name),
+                listener.endpoint.clone(),
+            ));
+        }
+
+        let mut bound: Vec<(&str, Option<IpAddr>, u16)> = Vec::new();
+        for (name, endpoint) in &listeners {
+            let ip = match &endpoint.addr {
+                None => {
+                    problems.push(BrokerConfigProblem::MissingAddr {
+                        endpoint: name.to_string(),
+                    });
+                    None
+                }
+                Some(addr) if addr == "localhost" => Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                Some(addr) => match addr.parse::<IpAddr>() {
+                    Ok(ip) => Some(ip),
+                    Err(_) => {
+                        problems.push(BrokerConfigProblem::InvalidAddr {
+                            endpoint: name.to_string(),
+                            addr: addr.clone(),
+                        });
+                        None
+                    }
+                },
+            };
+            let Some(port) = endpoint.port else {
+                problems.push(BrokerConfigProblem::MissingPort {
+                    endpoint: name.to_string(),
+                });
+                continue;
+            };
+            // Port 0 is resolved to a free port by the system
+            if port == 0 {
+                continue;
+            }
+            if port < 1024 {
+                problems.push(BrokerConfigProblem::PrivilegedPort {
+                    endpoint: name.to_string(),
+                    port,
+                });
+            }
+            for (other, other_ip, other_port) in &bound {
+                let overlap = match (ip, other_ip) {
+                    (Some(ip), Some(other_ip)) => {
+                        ip == *other_ip || ip.is_unspecified() || other_ip.is_unspecified()
+                    }
+                    _ => false,
+                };
+                if port == *other_port && overlap {
+                    problems.push(BrokerConfigProblem::PortConflict {
+                        first: other.to_string(),
+                        second: name.to_string(),
+                        port,
+                    });
+                }
+            }
+            bound.push((name.as_str(), ip, port));
+        }
+
+        // Without listener, only in-process clients can reach the broker
+        if listeners.is_empty() && self.local_only != Some(true) {
+            problems.push(BrokerConfigProblem::NoListener);
+        }
+
+        // ACLs are enforced and messages recorded by the relay, which only
+        // reads plain MQTT
+        let uninspected = self.uninspected_listeners();
+        if self.acl.is_some() {
+            for endpoint in &uninspected {
+                problems.push(BrokerConfigProblem::AclNotEnforced {
+                    endpoint: endpoint.clone(),
+                });
+            }
+        }
+        if self.recorder.is_some() {
+            for endpoint in &uninspected {
+                problems.push(BrokerConfigProblem::NotRecorded {
+                    endpoint: endpoint.clone(),
+                });
+            }
+        }
+
+        for (name, endpoint) in [("tls", &self.tls), ("wss", &self.wss)] {
+            let Some(endpoint) = endpoint else {
+                continue;
+            };
+            if endpoint.cert_path.is_none() {
+                problems.push(BrokerConfigProblem::MissingTlsFile {
+                    endpoint: name.to_string(),
+                    field: "cert_path".to_string(),
+                });
+            }
+            if endpoint.key_path.is_none() {
+                problems.push(BrokerConfigProblem::MissingTlsFile {
+                    endpoint: name.to_string(),
+                    field: "key_path".to_string(),
+                });
+            }
+        }
+
+        if let Some(bridge) = &self.bridge {
+            if bridge.remote.addr.is_none() {
+                problems.push(BrokerConfigProblem::MissingAddr {
+                    endpoint: "bridge.remote".to_string(),
+                });
+            }
+            if bridge.remote.port.is_none() {
+                problems.push(BrokerConfigProblem::MissingPort {
+                    endpoint: "bridge.remote".to_string(),
+                });
+            }
+            for topic in &bridge.topics {
+                if !crate::rumqtt::topic::is_valid_filter(&topic.filter) {
+                    problems.push(BrokerConfigProblem::InvalidFilter {
+                        section: "bridge.topics".to_string(),
+                        filter: topic.filter.clone(),
+                    });
+                }
+            }
+        }
+
+        problems
+    }
 }
 
 // ============================================================================
@@ -101,6 +1072,21 @@ impl Default for MqttBrokerConfig {
                 port: Some(1883),
             }),
             websocket: None,
+            tls: None,
+            wss: None,
+            tcp_v5: None,
+            listeners: None,
+            local_only: None,
+            router: None,
+            connections: None,
+            auth: None,
+            acl: None,
+            stats: None,
+            bridge: None,
+            retained_store: None,
+            recorder: None,
+            credentials: None,
+            client: None,
         }
     }
 }
@@ -200,3 +1186,204 @@ where
         }
     }
 }
+
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ------------------------------------------------------------------------
+
+    /// Problems reported by the validation
+    fn problems(config: &MqttBrokerConfig) -> Vec<BrokerConfigProblem> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(e) => e.problems,
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    /// Endpoint on an address and a port
+    fn endpoint(addr: &str, port: u16) -> IPEndpointConfig {
+        IPEndpointConfig {
+            addr: Some(addr.into()),
+            port: Some(port),
+        }
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn default_configurations_are_valid() {
+        assert_eq!(problems(&MqttBrokerConfig::default()), []);
+        assert_eq!(problems(&MqttBrokerConfig::new_ephemeral()), []);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn no_listener_needs_local_only() {
+        let mut config = MqttBrokerConfig {
+            tcp: None,
+            ..Default::default()
+        };
+        assert_eq!(problems(&config), [BrokerConfigProblem::NoListener]);
+
+        config.local_only = Some(true);
+        assert_eq!(problems(&config), []);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn listeners_sharing_a_port_conflict() {
+        let mut config = MqttBrokerConfig {
+            tcp: Some(endpoint("127.0.0.1", 1883)),
+            websocket: Some(endpoint("0.0.0.0", 1883)),
+            listeners: Some(vec![MqttListenerConfig {
+                name: "lab".into(),
+                protocol: MqttListenerProtocol::Tcp,
+                endpoint: endpoint("192.168.1.2", 1883),
+                connections: None,
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(
+            problems(&config),
+            [
+                BrokerConfigProblem::PortConflict {
+                    first: "tcp".into(),
+                    second: "websocket".into(),
+                    port: 1883,
+                },
+                BrokerConfigProblem::PortConflict {
+                    first: "websocket".into(),
+                    second: "listeners.lab".into(),
+                    port: 1883,
+                },
+            ]
+        );
+
+        // Ephemeral ports never conflict
+        config.tcp = Some(endpoint("127.0.0.1", 0));
+        config.websocket = Some(endpoint("127.0.0.1", 0));
+        config.listeners = None;
+        assert_eq!(problems(&config), []);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = MqttBrokerConfig {
+            tcp: Some(IPEndpointConfig {
+                addr: Some("broker.local".into()),
+                port: None,
+            }),
+            websocket: Some(endpoint("127.0.0.1", 80)),
+            ..Default::default()
+        };
+        assert_eq!(
+            problems(&config),
+            [
+                BrokerConfigProblem::InvalidAddr {
+                    endpoint: "tcp".into(),
+                    addr: "broker.local".into(),
+                },
+                BrokerConfigProblem::MissingPort {
+                    endpoint: "tcp".into(),
+                },
+            ]
+        );
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn listener_limits_are_completed_field_by_field() {
+        let broker = MqttConnectionLimitsConfig {
+            connection_timeout_ms: Some(1000),
+            max_payload_size: Some(4096),
+            dynamic_filters: Some(false),
+            ..Default::default()
+        };
+        let listener = MqttConnectionLimitsConfig {
+            max_payload_size: Some(1024),
+            max_inflight_count: Some(10),
+            ..Default::default()
+        };
+        let merged = listener.or(&broker);
+        assert_eq!(merged.connection_timeout_ms, Some(1000));
+        assert_eq!(merged.max_payload_size, Some(1024));
+        assert_eq!(merged.max_inflight_count, Some(10));
+        assert_eq!(merged.dynamic_filters, Some(false));
+        assert_eq!(merged.next_connection_delay_ms, None);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn privileged_ports_are_only_warnings() {
+        let config = MqttBrokerConfig {
+            tcp: Some(endpoint("0.0.0.0", 1883)),
+            websocket: Some(endpoint("127.0.0.1", 80)),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let warning = BrokerConfigProblem::PrivilegedPort {
+            endpoint: "websocket".into(),
+            port: 80,
+        };
+        assert!(warning.is_warning());
+        assert_eq!(config.problems(), [warning]);
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn acl_needs_plain_tcp_listeners() {
+        let mut config = MqttBrokerConfig {
+            acl: Some(MqttAclConfig::default()),
+            ..Default::default()
+        };
+        assert_eq!(problems(&config), []);
+
+        config.websocket = Some(endpoint("127.0.0.1", 8083));
+        config.listeners = Some(vec![MqttListenerConfig {
+            name: "browser".into(),
+            protocol: MqttListenerProtocol::Websocket,
+            endpoint: endpoint("127.0.0.1", 8084),
+            connections: None,
+        }]);
+        assert_eq!(
+            problems(&config),
+            [
+                BrokerConfigProblem::AclNotEnforced {
+                    endpoint: "websocket".into(),
+                },
+                BrokerConfigProblem::AclNotEnforced {
+                    endpoint: "listeners.browser".into(),
+                },
+            ]
+        );
+    }
+
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn recorder_only_warns_about_unread_listeners() {
+        let config = MqttBrokerConfig {
+            recorder: Some(MqttRecorderConfig::default()),
+            websocket: Some(endpoint("127.0.0.1", 8083)),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let warning = BrokerConfigProblem::NotRecorded {
+            endpoint: "websocket".into(),
+        };
+        assert!(warning.is_warning());
+        assert_eq!(config.problems(), [warning]);
+    }
+}
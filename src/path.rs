@@ -1,5 +1,6 @@
 use std::fs;
 use std::io;
+use std::path::Path;
 use std::path::PathBuf;
 
 // -------------------------------------------------------------------------------
@@ -36,3 +37,21 @@ pub fn ensure_user_root_dir_exists() -> io::Result<()> {
 }
 
 // -------------------------------------------------------------------------------
+
+/// Resolve a path relative to the user root directory
+///
+/// Absolute paths are returned unchanged, relative ones are joined to `~/.panduza`.
+///
+/// # Returns
+/// `Some(PathBuf)` containing the resolved path,
+/// or `None` if the path is relative and the home directory cannot be determined.
+pub fn resolve_user_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else {
+        user_root_dir().map(|root| root.join(path))
+    }
+}
+
+// -------------------------------------------------------------------------------
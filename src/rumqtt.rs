@@ -6,4 +6,54 @@ pub mod broker;
 
 pub mod client;
 
+// -------------------------------------------------------------------------------
+
+/// MQTT v5 flavour of the client utilities
+pub mod client_v5;
+
+// -------------------------------------------------------------------------------
+
+/// Routing of incoming publishes to handlers by topic filter
+pub mod dispatch;
+
+// -------------------------------------------------------------------------------
+
+/// Managed event loop with automatic reconnection
+pub mod driver;
+
+// -------------------------------------------------------------------------------
+
+/// Typed JSON payload helpers
+pub mod json;
+
+// -------------------------------------------------------------------------------
+
+/// Queue of the messages published while disconnected
+pub mod offline_queue;
+
+// -------------------------------------------------------------------------------
+
+/// Per-message and per-topic publish settings
+pub mod publish;
+
+// -------------------------------------------------------------------------------
+
+/// Request/response pattern over MQTT
+pub mod rpc;
+
+// -------------------------------------------------------------------------------
+
+/// Cache of the last values of subscribed topics
+pub mod state_cache;
+
+// -------------------------------------------------------------------------------
+
+/// MQTT topic filter utilities
+pub mod topic;
+
+// -------------------------------------------------------------------------------
+
+/// Time, QoS, file and JSON payload helpers shared by the MQTT utilities
+pub mod util;
+
 // ===============================================================================
@@ -1,14 +1,71 @@
+pub mod acl;
+pub mod auth;
+pub mod bridge;
+pub mod handle;
+pub mod local;
+pub mod recorder;
+pub mod retained;
+pub mod stats;
+
+mod inspect;
+mod relay;
+
+use crate::config::BrokerConfigError;
 use crate::config::IPEndpointConfig;
+use crate::config::MqttBridgeConfig;
 use crate::config::MqttBrokerConfig;
-use anyhow::Ok;
+use crate::config::MqttConnectionLimitsConfig;
+use crate::config::MqttListenerProtocol;
+use crate::config::MqttRouterConfig;
+use crate::config::TlsEndpointConfig;
+use acl::Acl;
+use auth::Authenticator;
+use auth::RelaySecret;
 use config::Map;
 use config::Value;
+use handle::BrokerAddrs;
+use handle::BrokerHandle;
+use inspect::Inspection;
+use local::LocalHub;
+use local::Origin;
+use recorder::Recorder;
+use relay::RelayListener;
+use relay::UpstreamPorts;
+use retained::RetainedStore;
 use rumqttd::Broker;
 use rumqttd::Config;
+use stats::BrokerStats;
+use stats::SysPublisher;
+use tracing::error;
 use tracing::info;
 use tracing::warn;
 
-use std::thread::JoinHandle;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Maximum time given to the rumqttd core to bind its listeners
+const CORE_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum payload size of the listeners when not configured
+const DEFAULT_MAX_PAYLOAD_SIZE: usize = 20480;
+
+//------------------------------------------------------------------------------
+
+/// Error of a broker start
+#[derive(Debug, thiserror::Error)]
+pub enum BrokerError {
+    /// The configuration is not valid, with all its problems
+    #[error(transparent)]
+    Config(#[from] BrokerConfigError),
+
+    /// A listener could not be bound or the broker core did not start
+    #[error("unable to start the broker: {0:#}")]
+    Startup(anyhow::Error),
+}
+
+//------------------------------------------------------------------------------
 
 /// Start the MQTT broker in a separate thread
 #[deprecated(since = "0.1.0", note = "Use start_broker instead")]
@@ -65,12 +122,53 @@ pub fn start(ip_endpoint: &IPEndpointConfig) -> std::thread::JoinHandle<()> {
     //
     // start broker
     info!("Broker listen on: {}", listen_addr);
-    let _jh = std::thread::spawn(move || {
-        broker.start().unwrap();
-        println!("BROKER STOPPPED !!!!!!!!!!!!!!!!!");
-    });
+    std::thread::spawn(move || {
+        if let Err(e) = broker.start() {
+            error!("Broker stopped: {:?}", e);
+        }
+    })
+}
+
+//------------------------------------------------------------------------------
+
+/// Router configuration section
+///
+/// [router]
+/// id = 0
+/// max_connections = 20480
+/// max_outgoing_packet_count = 200
+/// max_segment_size = 104857600
+/// max_segment_count = 10
+///
+pub fn router_section(
+    router_config: &MqttRouterConfig,
+) -> std::collections::HashMap<String, Value> {
+    let max_connections = router_config.max_connections.unwrap_or(20480);
+    let max_outgoing_packet_count = router_config.max_outgoing_packet_count.unwrap_or(200);
+    let max_segment_size = router_config.max_segment_size.unwrap_or(104857600);
+    let max_segment_count = router_config.max_segment_count.unwrap_or(10);
+
+    let mut router: std::collections::HashMap<String, Value> = Map::new();
+    router.insert("id".to_string(), Value::new(None, 0));
+    router.insert(
+        "max_connections".to_string(),
+        Value::new(None, max_connections as u64),
+    );
+    router.insert(
+        "max_outgoing_packet_count".to_string(),
+        Value::new(None, max_outgoing_packet_count),
+    );
+    router.insert(
+        "max_segment_size".to_string(),
+        Value::new(None, max_segment_size as u64),
+    );
+    router.insert(
+        "max_segment_count".to_string(),
+        Value::new(None, max_segment_count as u64),
+    );
 
-    return _jh;
+    // return the object
+    router
 }
 
 //------------------------------------------------------------------------------
@@ -79,7 +177,7 @@ pub fn start(ip_endpoint: &IPEndpointConfig) -> std::thread::JoinHandle<()> {
 ///
 /// [ws.1]
 /// name = "ws-1"
-/// listen = "0.0.0.0:8083"
+/// listen = "127.0.0.1:<reserved port>"
 /// next_connection_delay_ms = 1
 ///     [ws.1.connections]
 ///     connection_timeout_ms = 60000
@@ -89,39 +187,66 @@ pub fn start(ip_endpoint: &IPEndpointConfig) -> std::thread::JoinHandle<()> {
 ///     max_inflight_count = 500
 ///     max_inflight_size = 1024
 ///
+/// `listen` is the loopback address the public listener forwards to. Values
+/// missing from `limits` fall back to the ones above.
 pub fn websocket_section(
-    broker_config: &MqttBrokerConfig,
+    listen_addr: SocketAddr,
+    limits: &MqttConnectionLimitsConfig,
 ) -> std::collections::HashMap<String, Value> {
-    // extract host and port
-    let host: &str = broker_config
-        .websocket
-        .as_ref()
-        .unwrap()
-        .addr
-        .as_ref()
-        .unwrap();
-    let port = broker_config.websocket.as_ref().unwrap().port.unwrap();
-    let listen_addr = format!("{}:{}", host, port);
+    let connection_timeout_ms = limits.connection_timeout_ms.unwrap_or(60000);
+    let max_client_id_len = limits.max_client_id_len.unwrap_or(256);
+    let throttle_delay_ms = limits.throttle_delay_ms.unwrap_or(0);
+    let max_payload_size = limits.max_payload_size.unwrap_or(DEFAULT_MAX_PAYLOAD_SIZE);
+    let max_inflight_count = limits.max_inflight_count.unwrap_or(500);
+    let max_inflight_size = limits.max_inflight_size.unwrap_or(1024);
+    let next_connection_delay_ms = limits.next_connection_delay_ms.unwrap_or(1);
 
     // Connections settings
     let mut connections: std::collections::HashMap<String, config::Value> = Map::new();
-    connections.insert("connection_timeout_ms".to_string(), Value::new(None, 60000));
-    connections.insert("max_client_id_len".to_string(), Value::new(None, 256));
-    connections.insert("throttle_delay_ms".to_string(), Value::new(None, 0));
-    connections.insert("max_payload_size".to_string(), Value::new(None, 20480));
-    connections.insert("max_inflight_count".to_string(), Value::new(None, 500));
-    connections.insert("max_inflight_size".to_string(), Value::new(None, 1024));
+    connections.insert(
+        "connection_timeout_ms".to_string(),
+        Value::new(None, connection_timeout_ms),
+    );
+    connections.insert(
+        "max_client_id_len".to_string(),
+        Value::new(None, max_client_id_len as u64),
+    );
+    connections.insert(
+        "throttle_delay_ms".to_string(),
+        Value::new(None, throttle_delay_ms),
+    );
+    connections.insert(
+        "max_payload_size".to_string(),
+        Value::new(None, max_payload_size as u64),
+    );
+    connections.insert(
+        "max_inflight_count".to_string(),
+        Value::new(None, max_inflight_count as u64),
+    );
+    connections.insert(
+        "max_inflight_size".to_string(),
+        Value::new(None, max_inflight_size as u64),
+    );
+    if let Some(dynamic_filters) = limits.dynamic_filters {
+        connections.insert(
+            "dynamic_filters".to_string(),
+            Value::new(None, dynamic_filters),
+        );
+    }
 
     // Server settings
     let mut ws: std::collections::HashMap<String, Value> = Map::new();
     ws.insert("name".to_string(), Value::new(None, "ws-1"));
-    ws.insert("listen".to_string(), Value::new(None, listen_addr.clone()));
-    ws.insert("next_connection_delay_ms".to_string(), Value::new(None, 1));
+    ws.insert(
+        "listen".to_string(),
+        Value::new(None, listen_addr.to_string()),
+    );
+    ws.insert(
+        "next_connection_delay_ms".to_string(),
+        Value::new(None, next_connection_delay_ms),
+    );
     ws.insert("connections".to_string(), Value::new(None, connections));
 
-    // Logging
-    info!("Broker listen on **ws**:{}", listen_addr);
-
     // return the object
     ws
 }
@@ -130,77 +255,624 @@ pub fn websocket_section(
 
 /// TCPv4 configuration section
 ///
-pub fn tcpv4_section(broker_config: &MqttBrokerConfig) -> std::collections::HashMap<String, Value> {
-    // extract host and port
-    let host: &str = broker_config.tcp.as_ref().unwrap().addr.as_ref().unwrap();
-    let port = broker_config.tcp.as_ref().unwrap().port.unwrap();
-    let listen_addr = format!("{}:{}", host, port);
+/// [v4.1]
+/// name = "v4-1"
+/// listen = "127.0.0.1:<reserved port>"
+/// next_connection_delay_ms = 1
+///     [v4.1.connections]
+///     connection_timeout_ms = 60000
+///     max_payload_size = 20480
+///     max_inflight_count = 20480
+///     dynamic_filters = true
+///
+/// `listen` is the loopback address the public listener forwards to. Values
+/// missing from `limits` fall back to the ones above.
+pub fn tcpv4_section(
+    listen_addr: SocketAddr,
+    limits: &MqttConnectionLimitsConfig,
+) -> std::collections::HashMap<String, Value> {
+    let connection_timeout_ms = limits.connection_timeout_ms.unwrap_or(60000);
+    let max_payload_size = limits.max_payload_size.unwrap_or(DEFAULT_MAX_PAYLOAD_SIZE);
+    let max_inflight_count = limits.max_inflight_count.unwrap_or(20480);
+    let dynamic_filters = limits.dynamic_filters.unwrap_or(true);
+    let next_connection_delay_ms = limits.next_connection_delay_ms.unwrap_or(1);
 
     // Connections settings
     let mut connections: std::collections::HashMap<String, config::Value> = Map::new();
-    connections.insert("connection_timeout_ms".to_string(), Value::new(None, 60000));
-    connections.insert("max_payload_size".to_string(), Value::new(None, 20480));
-    connections.insert("max_inflight_count".to_string(), Value::new(None, 20480));
-    connections.insert("dynamic_filters".to_string(), Value::new(None, true));
+    connections.insert(
+        "connection_timeout_ms".to_string(),
+        Value::new(None, connection_timeout_ms),
+    );
+    connections.insert(
+        "max_payload_size".to_string(),
+        Value::new(None, max_payload_size as u64),
+    );
+    connections.insert(
+        "max_inflight_count".to_string(),
+        Value::new(None, max_inflight_count as u64),
+    );
+    connections.insert(
+        "dynamic_filters".to_string(),
+        Value::new(None, dynamic_filters),
+    );
 
     // Server settings
     let mut tcp: std::collections::HashMap<String, Value> = Map::new();
     tcp.insert("name".to_string(), Value::new(None, "v4-1"));
-    tcp.insert("listen".to_string(), Value::new(None, listen_addr.clone()));
-    tcp.insert("next_connection_delay_ms".to_string(), Value::new(None, 1));
+    tcp.insert(
+        "listen".to_string(),
+        Value::new(None, listen_addr.to_string()),
+    );
+    tcp.insert(
+        "next_connection_delay_ms".to_string(),
+        Value::new(None, next_connection_delay_ms),
+    );
     tcp.insert("connections".to_string(), Value::new(None, connections));
 
-    // Logging
-    info!("Broker listen on **tcp**:{}", listen_addr);
-
     // return the object
     tcp
 }
 
 //------------------------------------------------------------------------------
 
-/// Start the broker
-/// This function will start the MQTT broker with the given configuration.
-pub fn start_broker_in_thread(broker_config: MqttBrokerConfig) -> anyhow::Result<JoinHandle<()>> {
+/// TLS settings of a listener section
+///
+/// [v4.2.tls]
+/// certpath = "/home/user/.panduza/certs/broker.pem"
+/// keypath = "/home/user/.panduza/certs/broker.key"
+///
+/// Relative paths are resolved against the user root directory.
+pub fn tls_settings(
+    endpoint: &TlsEndpointConfig,
+) -> anyhow::Result<std::collections::HashMap<String, Value>> {
+    let cert_path = endpoint
+        .resolved_cert_path()
+        .ok_or_else(|| anyhow::anyhow!("TLS endpoint without certificate path"))?;
+    let key_path = endpoint
+        .resolved_key_path()
+        .ok_or_else(|| anyhow::anyhow!("TLS endpoint without private key path"))?;
+
+    // rumqttd only reports these errors from its own threads
+    if !cert_path.is_file() {
+        anyhow::bail!("TLS certificate not found: {}", cert_path.display());
+    }
+    if !key_path.is_file() {
+        anyhow::bail!("TLS private key not found: {}", key_path.display());
+    }
+
+    let mut tls: std::collections::HashMap<String, Value> = Map::new();
+    tls.insert(
+        "certpath".to_string(),
+        Value::new(None, cert_path.to_string_lossy().to_string()),
+    );
+    tls.insert(
+        "keypath".to_string(),
+        Value::new(None, key_path.to_string_lossy().to_string()),
+    );
+
+    // return the object
+    Ok(tls)
+}
+
+//------------------------------------------------------------------------------
+
+/// Public listen address of an endpoint
+fn endpoint_listen_addr(endpoint: &IPEndpointConfig) -> anyhow::Result<String> {
+    let host = endpoint
+        .addr
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Broker endpoint without address"))?;
+    let port = endpoint
+        .port
+        .ok_or_else(|| anyhow::anyhow!("Broker endpoint without port"))?;
+    Ok(format!("{}:{}", host, port))
+}
+
+//------------------------------------------------------------------------------
+
+/// Limits of a listener: its own ones, if any, completed field by field by the
+/// ones of the broker
+fn listener_limits(
+    own: Option<&MqttConnectionLimitsConfig>,
+    broker: &MqttConnectionLimitsConfig,
+) -> MqttConnectionLimitsConfig {
+    match own {
+        Some(own) => own.or(broker),
+        None => broker.clone(),
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Result channel of the rumqttd core thread
+type CoreResult = tokio::sync::oneshot::Receiver<Result<(), rumqttd::Error>>;
+
+//------------------------------------------------------------------------------
+
+/// Broker whose listeners are bound and whose core is running
+struct LaunchedBroker {
+    /// Public listeners, not served yet
+    listeners: Vec<RelayListener>,
+
+    /// Bound addresses of the public listeners
+    addrs: BrokerAddrs,
+
+    /// End result of the rumqttd core
+    core: CoreResult,
+
+    /// Statistics collected by the relay
+    stats: Option<Arc<BrokerStats>>,
+
+    /// Publisher of the statistics on the broker
+    sys: Option<SysPublisher>,
+
+    /// Link of the local clients and its receiving half
+    local: (Arc<LocalHub>, rumqttd::local::LinkRx),
+
+    /// Bridge to a remote broker
+    bridge: Option<MqttBridgeConfig>,
+
+    /// Persistence of the retained messages
+    retained: Option<Arc<RetainedStore>>,
+
+    /// Recording of the published messages
+    recorder: Option<Arc<Recorder>>,
+}
+
+//------------------------------------------------------------------------------
+
+/// Validate the configuration, then start the broker
+async fn launch_broker(broker_config: MqttBrokerConfig) -> Result<LaunchedBroker, BrokerError> {
     //
     // info
     info!("----- SERVICE : START BROKER -----");
 
-    let mut router: std::collections::HashMap<String, Value> = Map::new();
-    router.insert("id".to_string(), Value::new(None, 0));
-    router.insert("max_connections".to_string(), Value::new(None, 20480));
-    router.insert(
-        "max_outgoing_packet_count".to_string(),
-        Value::new(None, 200),
-    );
-    router.insert("max_segment_size".to_string(), Value::new(None, 104857600));
-    router.insert("max_segment_count".to_string(), Value::new(None, 10));
+    // report configuration problems before binding anything
+    broker_config.validate()?;
+    launch_validated_broker(broker_config)
+        .await
+        .map_err(BrokerError::Startup)
+}
+
+//------------------------------------------------------------------------------
+
+/// Bind the public listeners, start the rumqttd core and wait until it accepts
+/// connections
+async fn launch_validated_broker(
+    broker_config: MqttBrokerConfig,
+) -> anyhow::Result<LaunchedBroker> {
+    let router_config = broker_config.router.clone().unwrap_or_default();
+    let limits = broker_config.connections.clone().unwrap_or_default();
 
     // see docs of config crate to know more
     let mut config_builder = config::Config::builder()
         .set_default("id", 0)?
-        .set_default("router", router)?;
+        .set_default("router", router_section(&router_config))?;
+
+    // Public listeners, bound now to report errors and resolved ports to the caller
+    let mut listeners = Vec::new();
+    let mut upstreams = UpstreamPorts::default();
+    let mut addrs = BrokerAddrs::default();
+
+    // Statistics are recorded by the public listeners
+    let stats = broker_config
+        .stats
+        .as_ref()
+        .map(|_| Arc::new(BrokerStats::default()));
+
+    // Retained messages are copied from the link of the local clients
+    let retained = match &broker_config.retained_store {
+        Some(store_config) => Some(Arc::new(RetainedStore::open(store_config)?)),
+        None => None,
+    };
+
+    // Published messages are recorded by the public listeners and the local
+    // clients
+    let recorder = match &broker_config.recorder {
+        Some(recorder_config) => Some(Arc::new(Recorder::open(recorder_config)?)),
+        None => None,
+    };
+
+    // only the users of the auth section can connect
+    let authenticator = match &broker_config.auth {
+        Some(auth_config) => Some(Arc::new(Authenticator::load(auth_config)?)),
+        None => None,
+    };
+
+    // ACLs, statistics and the recorder need the relay to read the packets,
+    // which it can only do on plain MQTT. The core listeners behind it then
+    // only accept the relay, which checks the credentials itself.
+    let inspected = broker_config.acl.is_some() || stats.is_some() || recorder.is_some();
+    let relay_secret = RelaySecret::generate();
+    let inspection = |limits: &MqttConnectionLimitsConfig| {
+        inspected.then(|| {
+            Arc::new(Inspection {
+                acl: broker_config.acl.as_ref().map(Acl::new),
+                auth: authenticator.clone(),
+                secret: relay_secret.clone(),
+                max_packet_size: limits.max_payload_size.unwrap_or(DEFAULT_MAX_PAYLOAD_SIZE),
+                recorder: recorder.clone(),
+            })
+        })
+    };
+    if broker_config.acl.is_some() && broker_config.auth.is_none() {
+        warn!(
+            "Broker ACL enabled without auth: client ids and user names are not \
+             authenticated, any client can claim them"
+        );
+    }
 
     // Only add TCP section if tcp config is present
-    if broker_config.tcp.is_some() {
-        config_builder = config_builder.set_default("v4.1", tcpv4_section(&broker_config))?;
+    if let Some(tcp) = &broker_config.tcp {
+        let upstream = upstreams.reserve()?;
+        let tcp_limits = listener_limits(None, &limits);
+        config_builder =
+            config_builder.set_default("v4.1", tcpv4_section(upstream, &tcp_limits))?;
+        let mut listener = RelayListener::bind("v4-1", &endpoint_listen_addr(tcp)?, upstream)?;
+        listener.inspection = inspection(&tcp_limits);
+        if broker_config.acl.is_some() {
+            info!("Broker ACL enabled on **tcp**");
+        }
+        info!("Broker listen on **tcp**:{}", listener.local_addr);
+        addrs.tcp = Some(listener.local_addr);
+        listeners.push(listener);
     }
 
     // Only add WebSocket section if websocket config is present
-    if broker_config.websocket.is_some() {
-        config_builder = config_builder.set_default("ws.1", websocket_section(&broker_config))?;
+    if let Some(websocket) = &broker_config.websocket {
+        let upstream = upstreams.reserve()?;
+        let websocket_limits = listener_limits(None, &limits);
+        config_builder =
+            config_builder.set_default("ws.1", websocket_section(upstream, &websocket_limits))?;
+        let listener = RelayListener::bind("ws-1", &endpoint_listen_addr(websocket)?, upstream)?;
+        info!("Broker listen on **ws**:{}", listener.local_addr);
+        addrs.websocket = Some(listener.local_addr);
+        listeners.push(listener);
+    }
+
+    // Only add TLS section if tls config is present
+    if let Some(tls) = &broker_config.tls {
+        let upstream = upstreams.reserve()?;
+        let mut section = tcpv4_section(upstream, &listener_limits(None, &limits));
+        section.insert("name".to_string(), Value::new(None, "tls-1"));
+        section.insert("tls".to_string(), Value::new(None, tls_settings(tls)?));
+        config_builder = config_builder.set_default("v4.2", section)?;
+        let listen_addr = endpoint_listen_addr(&tls.ip_endpoint())?;
+        let listener = RelayListener::bind("tls-1", &listen_addr, upstream)?;
+        info!("Broker listen on **tls**:{}", listener.local_addr);
+        addrs.tls = Some(listener.local_addr);
+        listeners.push(listener);
+    }
+
+    // Only add secure WebSocket section if wss config is present
+    if let Some(wss) = &broker_config.wss {
+        let upstream = upstreams.reserve()?;
+        let mut section = websocket_section(upstream, &listener_limits(None, &limits));
+        section.insert("name".to_string(), Value::new(None, "wss-1"));
+        section.insert("tls".to_string(), Value::new(None, tls_settings(wss)?));
+        config_builder = config_builder.set_default("ws.2", section)?;
+        let listen_addr = endpoint_listen_addr(&wss.ip_endpoint())?;
+        let listener = RelayListener::bind("wss-1", &listen_addr, upstream)?;
+        info!("Broker listen on **wss**:{}", listener.local_addr);
+        addrs.wss = Some(listener.local_addr);
+        listeners.push(listener);
+    }
+
+    // Only add MQTT v5 section if tcp_v5 config is present
+    if let Some(tcp_v5) = &broker_config.tcp_v5 {
+        let upstream = upstreams.reserve()?;
+        let tcp_v5_limits = listener_limits(None, &limits);
+        let mut section = tcpv4_section(upstream, &tcp_v5_limits);
+        section.insert("name".to_string(), Value::new(None, "v5-1"));
+        config_builder = config_builder.set_default("v5.1", section)?;
+        let mut listener = RelayListener::bind("v5-1", &endpoint_listen_addr(tcp_v5)?, upstream)?;
+        listener.inspection = inspection(&tcp_v5_limits);
+        if broker_config.acl.is_some() {
+            info!("Broker ACL enabled on **tcp_v5**");
+        }
+        info!("Broker listen on **tcp_v5**:{}", listener.local_addr);
+        addrs.tcp_v5 = Some(listener.local_addr);
+        listeners.push(listener);
+    }
+
+    // Named listeners come after the single endpoints, from v4.3 and ws.3
+    let mut next_v4 = 3;
+    let mut next_ws = 3;
+    for named in broker_config.listeners.iter().flatten() {
+        let upstream = upstreams.reserve()?;
+        let named_limits = listener_limits(named.connections.as_ref(), &limits);
+        let (key, mut section) = match named.protocol {
+            MqttListenerProtocol::Tcp => {
+                let key = format!("v4.{}", next_v4);
+                next_v4 += 1;
+                (key, tcpv4_section(upstream, &named_limits))
+            }
+            MqttListenerProtocol::Websocket => {
+                let key = format!("ws.{}", next_ws);
+                next_ws += 1;
+                (key, websocket_section(upstream, &named_limits))
+            }
+        };
+        section.insert("name".to_string(), Value::new(None, named.name.clone()));
+        config_builder = config_builder.set_default(&key, section)?;
+        let listen_addr = endpoint_listen_addr(&named.endpoint)?;
+        let mut listener = RelayListener::bind(named.name.clone(), &listen_addr, upstream)?;
+        if named.protocol == MqttListenerProtocol::Tcp {
+            listener.inspection = inspection(&named_limits);
+        }
+        info!(
+            "Broker listen on **{}**:{}",
+            named.name, listener.local_addr
+        );
+        addrs.named.insert(named.name.clone(), listener.local_addr);
+        listeners.push(listener);
+    }
+
+    for listener in listeners.iter_mut() {
+        listener.stats = stats.clone();
     }
 
     let config = config_builder.build()?;
     //
     // this is where we deserialize it into Config
-    let rumqttd_config: Config = config.try_deserialize()?;
+    let mut rumqttd_config: Config = config.try_deserialize()?;
+
+    //
+    // the relay checks the credentials of the clients of inspected listeners,
+    // the core checks the other ones
+    if let Some(authenticator) = &authenticator {
+        authenticator.install(&mut rumqttd_config);
+        info!("Broker authentication enabled");
+    }
+    let relayed: Vec<String> = listeners
+        .iter()
+        .filter(|listener| listener.inspection.is_some())
+        .map(|listener| listener.name.clone())
+        .collect();
+    relay_secret.install(&mut rumqttd_config, &relayed);
+
     let mut broker = Broker::new(rumqttd_config);
 
     //
-    // start broker
-    return Ok(std::thread::spawn(move || {
-        let end_result = broker.start();
-        warn!("BROKER STOPPPED {:?} !!!!!!!!!!!!!!!!!", end_result);
-    }));
+    // in-process clients share a link, which can only be created before start
+    let (local_tx, local_rx) = broker
+        .link(local::LOCAL_LINK_ID)
+        .map_err(|e| anyhow::anyhow!("Unable to link to broker core: {:?}", e))?;
+    let local = (
+        Arc::new(LocalHub::new(
+            local_tx,
+            retained.clone(),
+            recorder.clone(),
+            broker_config.acl.as_ref().map(Acl::new),
+        )),
+        local_rx,
+    );
+    local
+        .0
+        .subscribe_all()
+        .map_err(|e| anyhow::anyhow!("Unable to subscribe the local client link: {}", e))?;
+
+    //
+    // the core reports its own totals, which also cover the listeners and
+    // clients the relay does not see
+    if let Some(stats) = &stats {
+        let meters = broker
+            .meters()
+            .map_err(|e| anyhow::anyhow!("Unable to link to broker core meters: {:?}", e))?;
+        stats.watch_core(meters)?;
+    }
+
+    //
+    // the statistics are published through a local link of the core
+    let mut sys = None;
+    if let Some(stats_config) = &broker_config.stats {
+        if let Some(interval) = stats_config.sys_interval_secs {
+            let (tx, rx) = broker
+                .link("broker-stats")
+                .map_err(|e| anyhow::anyhow!("Unable to link to broker core: {:?}", e))?;
+            let prefix = stats_config
+                .sys_prefix
+                .clone()
+                .unwrap_or_else(|| stats::DEFAULT_SYS_PREFIX.to_string());
+            info!("Broker statistics published on **{}**", prefix);
+            sys = Some(SysPublisher {
+                tx,
+                rx,
+                prefix,
+                interval: Duration::from_secs(interval),
+            });
+        }
+    }
+
+    //
+    // start broker core, it has no way to be stopped so the thread is detached
+    // and keeps running until the process exits
+    upstreams.release()?;
+    let (core_tx, mut core) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name("broker-core".into())
+        .spawn(move || {
+            let end_result = broker.start();
+            let _ = core_tx.send(end_result);
+        })?;
+
+    //
+    // wait for the core listeners, their ports are then held by the core
+    for listener in &listeners {
+        wait_core_listener(listener.upstream, &mut core).await?;
+    }
+    drop(upstreams);
+
+    //
+    // restore the retained messages before serving the clients
+    if let Some(store) = &retained {
+        for message in store.messages() {
+            let qos = message.qos();
+            local
+                .0
+                .publish(Origin::Broker, message.topic, qos, true, message.payload)
+                .map_err(|e| anyhow::anyhow!("Unable to restore retained messages: {}", e))?;
+        }
+        info!("Broker retained messages restored");
+    }
+
+    Ok(LaunchedBroker {
+        listeners,
+        addrs,
+        core,
+        stats,
+        sys,
+        local,
+        bridge: broker_config.bridge,
+        retained,
+        recorder,
+    })
+}
+
+//------------------------------------------------------------------------------
+
+/// Wait until a rumqttd listener accepts connections
+async fn wait_core_listener(upstream: SocketAddr, core: &mut CoreResult) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + CORE_STARTUP_TIMEOUT;
+    loop {
+        if tokio::net::TcpStream::connect(upstream).await.is_ok() {
+            return Ok(());
+        }
+        match core.try_recv() {
+            Ok(end_result) => {
+                return Err(anyhow::anyhow!(
+                    "Broker stopped during startup: {:?}",
+                    end_result
+                ))
+            }
+            Err(tokio::sync::oneshot::error::TryRecvError::Closed) => {
+                return Err(anyhow::anyhow!(
+                    "Broker core thread panicked during startup"
+                ))
+            }
+            Err(tokio::sync::oneshot::error::TryRecvError::Empty) => {}
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow::anyhow!(
+                "Broker core not listening on {} after {:?}",
+                upstream,
+                CORE_STARTUP_TIMEOUT
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Split a launched broker into its handle and the future serving it
+///
+/// The future resolves once the broker is stopped, after all its listener
+/// sockets have been closed.
+fn serve_broker(launched: LaunchedBroker) -> (BrokerHandle, impl Future<Output = ()> + Send) {
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+    let LaunchedBroker {
+        listeners,
+        addrs,
+        core,
+        stats,
+        sys,
+        local: (local_hub, local_rx),
+        bridge,
+        retained,
+        recorder,
+    } = launched;
+    let handle = BrokerHandle::new(shutdown_tx, finished_rx, addrs, local_hub.clone())
+        .with_stats(stats.clone())
+        .with_retained(retained.clone())
+        .with_recorder(recorder.clone());
+
+    let service = async move {
+        let end_result = tokio::select! {
+            served = relay::serve(listeners, shutdown_rx) => served,
+            core = core => {
+                warn!("BROKER STOPPED {:?}", core);
+                match core {
+                    Ok(end_result) => end_result.map_err(|e| anyhow::anyhow!("{:?}", e)),
+                    Err(_) => Err(anyhow::anyhow!("Broker core thread panicked")),
+                }
+            }
+            _ = stats::run(stats, sys) => Ok(()),
+            _ = local::run(local_hub.clone(), local_rx) => Ok(()),
+            _ = bridge::run(bridge, local_hub) => Ok(()),
+            _ = retained::run(retained.clone()) => Ok(()),
+            _ = recorder::run(recorder.clone()) => Ok(()),
+        };
+        if let Some(store) = &retained {
+            store.persist();
+        }
+        if let Some(recorder) = &recorder {
+            recorder.flush();
+        }
+        let _ = finished_tx.send(end_result);
+    };
+
+    (handle, service)
+}
+
+//------------------------------------------------------------------------------
+
+/// Start the broker on the current tokio runtime
+///
+/// Returns once the broker listeners are bound and accept connections. The
+/// returned [`BrokerHandle`] reports the bound listener addresses, which is
+/// how to discover the ports picked for endpoints configured with port 0. It
+/// can be awaited to wait for the broker to stop.
+///
+/// The configuration is validated first, its problems are reported as a
+/// [`BrokerError::Config`].
+pub async fn start_broker(broker_config: MqttBrokerConfig) -> Result<BrokerHandle, BrokerError> {
+    let launched = launch_broker(broker_config).await?;
+    let (handle, service) = serve_broker(launched);
+    tokio::spawn(service);
+    Ok(handle)
+}
+
+//------------------------------------------------------------------------------
+
+/// Start the broker
+/// This function will start the MQTT broker with the given configuration.
+///
+/// The rumqttd core runs in its own threads and only listens on the loopback
+/// interface. The public listeners are served from a dedicated thread and can
+/// be closed through the returned [`BrokerHandle`]. Returns once the broker
+/// accepts connections, or a [`BrokerError::Config`] if the configuration is
+/// not valid.
+pub fn start_broker_in_thread(
+    broker_config: MqttBrokerConfig,
+) -> Result<BrokerHandle, BrokerError> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| BrokerError::Startup(e.into()))?;
+
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    let thread = std::thread::Builder::new()
+        .name("broker".into())
+        .spawn(move || {
+            runtime.block_on(async move {
+                match launch_broker(broker_config).await {
+                    Ok(launched) => {
+                        let (handle, service) = serve_broker(launched);
+                        let _ = ready_tx.send(Ok(handle));
+                        service.await;
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                    }
+                }
+            })
+        })
+        .map_err(|e| BrokerError::Startup(e.into()))?;
+
+    let handle = ready_rx.recv().map_err(|_| {
+        BrokerError::Startup(anyhow::anyhow!("Broker thread panicked during startup"))
+    })??;
+    Ok(handle.with_thread(thread))
 }
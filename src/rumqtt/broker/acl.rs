@@ -0,0 +1,274 @@
+use crate::config::MqttAclAction;
+use crate::config::MqttAclConfig;
+use crate::config::MqttAclPermission;
+use crate::config::MqttAclRuleConfig;
+use crate::rumqtt::topic::filter_covers;
+use crate::rumqtt::topic::filters_intersect;
+use tracing::warn;
+
+// =============================================================================
+
+/// Identity of a client, as announced in its CONNECT packet
+#[derive(Clone, Debug, Default)]
+pub struct ClientIdentity {
+    /// Client ID
+    pub client_id: String,
+
+    /// User name, if the client sent credentials
+    pub username: Option<String>,
+}
+
+// =============================================================================
+
+/// ACL rule with its topics already prefixed
+struct AclRule {
+    /// Users the rule applies to
+    users: Option<Vec<String>>,
+
+    /// Client ID prefixes the rule applies to
+    client_ids: Option<Vec<String>>,
+
+    /// Operation controlled by the rule
+    action: MqttAclAction,
+
+    /// Permission granted by the rule
+    permission: MqttAclPermission,
+
+    /// Topic filters of the rule
+    topics: Vec<String>,
+}
+
+// =============================================================================
+
+impl AclRule {
+    /// Build a rule from its configuration
+    fn new(config: &MqttAclRuleConfig) -> Self {
+        let topics = config
+            .topics
+            .iter()
+            .map(|topic| match &config.prefix {
+                Some(prefix) => format!("{}/{}", prefix, topic),
+                None => topic.clone(),
+            })
+            .collect();
+        Self {
+            users: config.users.clone(),
+            client_ids: config.client_ids.clone(),
+            action: config.action,
+            permission: config.permission,
+            topics,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if the rule applies to the client for this action
+    fn applies_to(&self, client: &ClientIdentity, action: MqttAclAction) -> bool {
+        let action_match = self.action == MqttAclAction::All || self.action == action;
+        let user_match = match &self.users {
+            Some(users) => client
+                .username
+                .as_ref()
+                .map(|username| users.contains(username))
+                .unwrap_or(false),
+            None => true,
+        };
+        let client_id_match = match &self.client_ids {
+            Some(prefixes) => prefixes
+                .iter()
+                .any(|prefix| client.client_id.starts_with(prefix.as_str())),
+            None => true,
+        };
+        action_match && user_match && client_id_match
+    }
+}
+
+// =============================================================================
+
+/// Compiled ACL of the broker
+pub struct Acl {
+    /// Rules, evaluated in order
+    rules: Vec<AclRule>,
+
+    /// Permission when no rule matches
+    default_permission: MqttAclPermission,
+}
+
+// =============================================================================
+
+impl Acl {
+    /// Compile the ACL configuration
+    pub fn new(config: &MqttAclConfig) -> Self {
+        Self {
+            rules: config.rules.iter().flatten().map(AclRule::new).collect(),
+            default_permission: config
+                .default_permission
+                .unwrap_or(MqttAclPermission::Allow),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if the client may publish on the topic
+    pub fn can_publish(&self, client: &ClientIdentity, topic: &str) -> bool {
+        let permission = self
+            .rules
+            .iter()
+            .filter(|rule| rule.applies_to(client, MqttAclAction::Publish))
+            .find(|rule| {
+                rule.topics
+                    .iter()
+                    .any(|pattern| filter_covers(pattern, topic))
+            })
+            .map(|rule| rule.permission)
+            .unwrap_or(self.default_permission);
+
+        if permission == MqttAclPermission::Deny {
+            warn!(
+                "ACL: publish on '{}' rejected for client '{}' (user {:?})",
+                topic, client.client_id, client.username
+            );
+        }
+        permission == MqttAclPermission::Allow
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if the client may subscribe to the filter
+    ///
+    /// An allow rule must cover the whole filter, while a deny rule only has to
+    /// overlap it.
+    pub fn can_subscribe(&self, client: &ClientIdentity, filter: &str) -> bool {
+        let permission = self
+            .rules
+            .iter()
+            .filter(|rule| rule.applies_to(client, MqttAclAction::Subscribe))
+            .find(|rule| {
+                rule.topics.iter().any(|pattern| match rule.permission {
+                    MqttAclPermission::Allow => filter_covers(pattern, filter),
+                    MqttAclPermission::Deny => filters_intersect(pattern, filter),
+                })
+            })
+            .map(|rule| rule.permission)
+            .unwrap_or(self.default_permission);
+
+        if permission == MqttAclPermission::Deny {
+            warn!(
+                "ACL: subscription to '{}' rejected for client '{}' (user {:?})",
+                filter, client.client_id, client.username
+            );
+        }
+        permission == MqttAclPermission::Allow
+    }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // -------------------------------------------------------------------------
+
+    /// Rule for all the clients
+    fn rule(
+        action: MqttAclAction,
+        permission: MqttAclPermission,
+        topics: &[&str],
+    ) -> MqttAclRuleConfig {
+        MqttAclRuleConfig {
+            users: None,
+            client_ids: None,
+            prefix: None,
+            action,
+            permission,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Client with a user name
+    fn client(client_id: &str, username: Option<&str>) -> ClientIdentity {
+        ClientIdentity {
+            client_id: client_id.to_string(),
+            username: username.map(str::to_string),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = Acl::new(&MqttAclConfig {
+            default_permission: Some(MqttAclPermission::Deny),
+            rules: Some(vec![
+                rule(
+                    MqttAclAction::Publish,
+                    MqttAclPermission::Deny,
+                    &["pza/secret/#"],
+                ),
+                rule(MqttAclAction::All, MqttAclPermission::Allow, &["pza/#"]),
+            ]),
+        });
+        let anyone = client("module", None);
+        assert!(!acl.can_publish(&anyone, "pza/secret/key"));
+        assert!(acl.can_publish(&anyone, "pza/psu/cmd"));
+        // The deny rule only covers publishing
+        assert!(acl.can_subscribe(&anyone, "pza/secret/#"));
+        // No rule matches, the default applies
+        assert!(!acl.can_publish(&anyone, "other/topic"));
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn rules_apply_to_their_users_and_client_ids() {
+        let mut dashboard = rule(MqttAclAction::Subscribe, MqttAclPermission::Allow, &["#"]);
+        dashboard.users = Some(vec!["dashboard".to_string()]);
+        dashboard.prefix = Some("pza".to_string());
+        let mut read_only = rule(MqttAclAction::All, MqttAclPermission::Deny, &["#"]);
+        read_only.users = Some(vec!["dashboard".to_string()]);
+        let mut psu = rule(MqttAclAction::Publish, MqttAclPermission::Deny, &["#"]);
+        psu.client_ids = Some(vec!["psu-".to_string()]);
+        let acl = Acl::new(&MqttAclConfig {
+            default_permission: None,
+            rules: Some(vec![dashboard, read_only, psu]),
+        });
+
+        let viewer = client("viewer", Some("dashboard"));
+        assert!(acl.can_subscribe(&viewer, "pza/psu/#"));
+        assert!(!acl.can_subscribe(&viewer, "other/#"));
+        assert!(!acl.can_publish(&viewer, "pza/psu/cmd"));
+
+        assert!(!acl.can_publish(&client("psu-1", None), "pza/psu/cmd"));
+        assert!(acl.can_publish(&client("scope-1", Some("bench")), "pza/psu/cmd"));
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn subscriptions_need_a_covering_allow_and_an_overlapping_deny() {
+        let acl = Acl::new(&MqttAclConfig {
+            default_permission: Some(MqttAclPermission::Allow),
+            rules: Some(vec![
+                rule(
+                    MqttAclAction::Subscribe,
+                    MqttAclPermission::Deny,
+                    &["pza/secret/#"],
+                ),
+                rule(
+                    MqttAclAction::Subscribe,
+                    MqttAclPermission::Allow,
+                    &["pza/+/att"],
+                ),
+            ]),
+        });
+        let anyone = client("module", None);
+        // Overlaps the deny rule
+        assert!(!acl.can_subscribe(&anyone, "pza/#"));
+        assert!(!acl.can_subscribe(&anyone, "pza/+/key"));
+        assert!(acl.can_subscribe(&anyone, "pza/psu/att"));
+        assert!(acl.can_subscribe(&anyone, "pza/psu/cmd"));
+    }
+}
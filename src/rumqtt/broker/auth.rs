@@ -0,0 +1,316 @@
+use crate::config::read_config;
+use crate::config::write_config;
+use crate::config::MqttAuthConfig;
+use argon2::password_hash::PasswordHash;
+use argon2::password_hash::PasswordHasher;
+use argon2::password_hash::PasswordVerifier;
+use argon2::password_hash::SaltString;
+use argon2::Argon2;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+use tracing::warn;
+
+// =============================================================================
+
+/// Password hashes of the broker users, stored as a JSON5 map
+/// `{ "user": "$argon2id$..." }`
+pub type PasswordHashes = HashMap<String, String>;
+
+// -----------------------------------------------------------------------------
+
+/// Hash a password into a PHC string
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(hash.to_string())
+}
+
+// -----------------------------------------------------------------------------
+
+/// Check a password against a PHC string
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Set the password of a user in a password file, creating the file if needed
+pub fn set_user_password(
+    password_file: &Path,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    let mut hashes: PasswordHashes = read_config(password_file)?;
+    hashes.insert(username.to_string(), hash_password(password)?);
+    write_config(password_file, &hashes)?;
+    info!("Password of user '{}' updated", username);
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+/// Remove a user from a password file
+pub fn remove_user(password_file: &Path, username: &str) -> anyhow::Result<()> {
+    let mut hashes: PasswordHashes = read_config(password_file)?;
+    if hashes.remove(username).is_some() {
+        write_config(password_file, &hashes)?;
+        info!("User '{}' removed", username);
+    }
+    Ok(())
+}
+
+// =============================================================================
+
+/// Checks the credentials of the clients connecting to the broker
+pub struct Authenticator {
+    /// Users allowed to connect
+    users: HashSet<String>,
+
+    /// Password hashes of the users
+    hashes: PasswordHashes,
+}
+
+// =============================================================================
+
+impl Authenticator {
+    /// Load the password file of the auth configuration
+    pub fn load(auth_config: &MqttAuthConfig) -> anyhow::Result<Self> {
+        let password_file = auth_config
+            .resolved_password_file()
+            .ok_or_else(|| anyhow::anyhow!("Unable to determine home directory"))?;
+        let hashes: PasswordHashes = read_config(&password_file)?;
+
+        let users: HashSet<String> = match &auth_config.users {
+            Some(users) => users.iter().cloned().collect(),
+            None => hashes.keys().cloned().collect(),
+        };
+        if users.is_empty() {
+            warn!(
+                "No broker user in {}, every connection will be refused",
+                password_file.display()
+            );
+        }
+        for user in &users {
+            if !hashes.contains_key(user) {
+                warn!(
+                    "User '{}' has no password in {}, it will not be able to connect",
+                    user,
+                    password_file.display()
+                );
+            }
+        }
+
+        Ok(Self { users, hashes })
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if the user is allowed and the password matches
+    pub fn check(&self, client_id: &str, username: &str, password: &str) -> bool {
+        let granted = self.users.contains(username)
+            && self
+                .hashes
+                .get(username)
+                .map(|hash| verify_password(password, hash))
+                .unwrap_or(false);
+        if !granted {
+            warn!(
+                "Connection refused for client '{}' with user '{}'",
+                client_id, username
+            );
+        }
+        granted
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Install the authenticator on all the listeners of a rumqttd config
+    pub fn install(self: &Arc<Self>, rumqttd_config: &mut rumqttd::Config) {
+        let servers = rumqttd_config
+            .v4
+            .iter_mut()
+            .chain(rumqttd_config.v5.iter_mut())
+            .chain(rumqttd_config.ws.iter_mut())
+            .flat_map(|servers| servers.values_mut());
+        for server in servers {
+            let authenticator = self.clone();
+            server
+                .connections
+                .set_auth_handler(move |client_id, username, password| {
+                    let authenticator = authenticator.clone();
+                    async move { authenticator.check(&client_id, &username, &password) }
+                });
+        }
+    }
+}
+
+// =============================================================================
+
+/// Password the relay gives to the rumqttd listeners it inspects
+///
+/// The core listens on loopback ports any local process can reach. The
+/// listeners behind an inspecting relay only accept this password, generated
+/// at each start, so the ACLs and the credentials checked by the relay cannot
+/// be bypassed by connecting to the core directly.
+#[derive(Clone)]
+pub struct RelaySecret(Arc<str>);
+
+// =============================================================================
+
+impl RelaySecret {
+    /// Generate a new random secret
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Self(secret.into())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Password presented by the relay
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if the password is the secret, compared in constant time
+    pub fn check(&self, password: &str) -> bool {
+        password.len() == self.0.len()
+            && password
+                .bytes()
+                .zip(self.0.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Only accept the secret on the named listeners of a rumqttd config
+    pub fn install(&self, rumqttd_config: &mut rumqttd::Config, listeners: &[String]) {
+        let servers = rumqttd_config
+            .v4
+            .iter_mut()
+            .chain(rumqttd_config.v5.iter_mut())
+            .flat_map(|servers| servers.values_mut())
+            .filter(|server| listeners.contains(&server.name));
+        for server in servers {
+            let secret = self.clone();
+            server
+                .connections
+                .set_auth_handler(move |client_id, _username, password| {
+                    let granted = secret.check(&password);
+                    if !granted {
+                        warn!(
+                            "Connection of client '{}' refused, it did not come through the relay",
+                            client_id
+                        );
+                    }
+                    async move { granted }
+                });
+        }
+    }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // -------------------------------------------------------------------------
+
+    /// Password file of a test, removed beforehand
+    fn password_file(name: &str) -> PathBuf {
+        let file = std::env::temp_dir().join(format!(
+            "pza-toolkit-auth-{}-{}.json5",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        file
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Auth configuration of a password file
+    fn auth_config(file: &Path, users: Option<&[&str]>) -> MqttAuthConfig {
+        MqttAuthConfig {
+            users: users.map(|users| users.iter().map(|user| user.to_string()).collect()),
+            password_file: Some(file.to_string_lossy().to_string()),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn hashed_passwords_verify() {
+        let hash = hash_password("secret").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("other", &hash));
+        assert!(!verify_password("secret", "not a hash"));
+        // Salted, the same password gives another hash
+        assert_ne!(hash, hash_password("secret").unwrap());
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn every_user_of_the_file_is_allowed_without_user_list() {
+        let file = password_file("all");
+        set_user_password(&file, "alice", "a-pass").unwrap();
+        set_user_password(&file, "bob", "b-pass").unwrap();
+
+        let authenticator = Authenticator::load(&auth_config(&file, None)).unwrap();
+        assert!(authenticator.check("client", "alice", "a-pass"));
+        assert!(authenticator.check("client", "bob", "b-pass"));
+        assert!(!authenticator.check("client", "bob", "a-pass"));
+        assert!(!authenticator.check("client", "carol", "c-pass"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn user_list_restricts_the_file() {
+        let file = password_file("list");
+        set_user_password(&file, "alice", "a-pass").unwrap();
+        set_user_password(&file, "bob", "b-pass").unwrap();
+
+        let authenticator = Authenticator::load(&auth_config(&file, Some(&["alice"]))).unwrap();
+        assert!(authenticator.check("client", "alice", "a-pass"));
+        assert!(!authenticator.check("client", "bob", "b-pass"));
+
+        remove_user(&file, "alice").unwrap();
+        let authenticator = Authenticator::load(&auth_config(&file, Some(&["alice"]))).unwrap();
+        assert!(!authenticator.check("client", "alice", "a-pass"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn relay_secrets_differ_at_each_start() {
+        let secret = RelaySecret::generate();
+        assert_eq!(secret.as_str().len(), 64);
+        assert!(secret.check(secret.as_str()));
+        assert!(!secret.check(""));
+        assert!(!secret.check(RelaySecret::generate().as_str()));
+    }
+}
@@ -0,0 +1,244 @@
+use super::local::LocalClient;
+use super::local::LocalHub;
+use crate::config::MqttBridgeConfig;
+use crate::config::MqttBridgeDirection;
+use crate::config::MqttBridgeTopicConfig;
+use crate::rumqtt::client::MqttClientBuilder;
+use crate::rumqtt::driver::ReconnectPolicy;
+use crate::rumqtt::publish::PublishOptions;
+use crate::rumqtt::topic::filter_matches;
+use bytes::Bytes;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// Name of the bridge when not configured
+const DEFAULT_NAME: &str = "bridge";
+
+/// Number of forwarded messages remembered to recognize their echoes
+const ECHO_CAPACITY: usize = 1000;
+
+/// Time after which the echo of a forwarded message is not expected anymore
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time after which a bridge which stopped is considered to have been running
+/// fine, and is restarted without delay
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+// =============================================================================
+
+/// Messages recently forwarded to the remote broker
+///
+/// A topic the bridge subscribes to on the remote broker comes back once
+/// forwarded, MQTT v3.1.1 having no way to opt out of its own messages. Only
+/// the messages on such topics are remembered, for a short time: an echo which
+/// never comes cannot hold a later identical message back.
+#[derive(Default)]
+struct EchoFilter {
+    /// Topics, payloads and forwarding times, oldest first
+    forwarded: VecDeque<(String, Bytes, Instant)>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl EchoFilter {
+    /// Remember a forwarded message whose echo is expected
+    fn forwarded(&mut self, topic: &str, payload: &Bytes) {
+        if self.forwarded.len() >= ECHO_CAPACITY {
+            self.forwarded.pop_front();
+        }
+        self.forwarded
+            .push_back((topic.to_string(), payload.clone(), Instant::now()));
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if the message is the echo of a forwarded one, which is forgotten
+    ///
+    /// An identical message of another client may be taken for the echo, the
+    /// echo then passes for it: both are the same.
+    fn is_echo(&mut self, topic: &str, payload: &Bytes) -> bool {
+        while let Some((_, _, at)) = self.forwarded.front() {
+            if at.elapsed() < ECHO_TIMEOUT {
+                break;
+            }
+            self.forwarded.pop_front();
+        }
+        match self
+            .forwarded
+            .iter()
+            .position(|(t, p, _)| t == topic && p == payload)
+        {
+            Some(position) => {
+                self.forwarded.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// =============================================================================
+
+/// Topic with a prefix, if any
+fn with_prefix(prefix: Option<&str>, topic: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{}/{}", prefix, topic),
+        None => topic.to_string(),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Move a topic from a prefix to another, `None` if it is out of `from`
+fn remap(topic: &str, from: Option<&str>, to: Option<&str>) -> Option<String> {
+    let relative = match from {
+        Some(from) => topic.strip_prefix(from)?.strip_prefix('/')?,
+        None => topic,
+    };
+    Some(with_prefix(to, relative))
+}
+
+// -----------------------------------------------------------------------------
+
+/// Topic of a message on the other broker, from the first matching rule
+///
+/// `outgoing` is true for the messages of the built-in broker.
+fn bridged_topic(topics: &[MqttBridgeTopicConfig], topic: &str, outgoing: bool) -> Option<String> {
+    topics.iter().find_map(|rule| {
+        let (enabled, from, to) = match outgoing {
+            true => (
+                rule.direction != MqttBridgeDirection::In,
+                rule.local_prefix.as_deref(),
+                rule.remote_prefix.as_deref(),
+            ),
+            false => (
+                rule.direction != MqttBridgeDirection::Out,
+                rule.remote_prefix.as_deref(),
+                rule.local_prefix.as_deref(),
+            ),
+        };
+        if !enabled || !filter_matches(&with_prefix(from, &rule.filter), topic) {
+            return None;
+        }
+        remap(topic, from, to)
+    })
+}
+
+// -----------------------------------------------------------------------------
+
+/// Forward messages between the built-in broker, through a local client, and
+/// a remote broker
+///
+/// The local client must not receive its own messages, see
+/// [`LocalClient::new_no_local`]. The connection to the remote broker is
+/// restored by itself. Messages of the built-in broker are dropped while the
+/// remote one is unreachable. Returns on an invalid topic filter, or once both
+/// clients are gone.
+pub async fn run_bridge(
+    config: MqttBridgeConfig,
+    local: LocalClient,
+    mut local_incoming: mpsc::Receiver<Publish>,
+) -> anyhow::Result<()> {
+    let name = config.name.as_deref().unwrap_or(DEFAULT_NAME);
+    let mut builder = MqttClientBuilder::new(name).endpoint(&config.remote);
+    if let Some(credentials) = &config.credentials {
+        builder = builder.credentials(credentials);
+    }
+    let (remote, mut remote_incoming) = builder.spawn_driver(ReconnectPolicy::default());
+
+    for rule in &config.topics {
+        if rule.direction != MqttBridgeDirection::In {
+            local
+                .subscribe(with_prefix(rule.local_prefix.as_deref(), &rule.filter))
+                .await?;
+        }
+        if rule.direction != MqttBridgeDirection::Out {
+            let filter = with_prefix(rule.remote_prefix.as_deref(), &rule.filter);
+            remote.subscribe(filter, QoS::AtLeastOnce).await?;
+        }
+    }
+    info!(
+        "Bridge '{}' to {:?}:{:?} started",
+        name, config.remote.addr, config.remote.port
+    );
+
+    let mut remote_echoes = EchoFilter::default();
+    loop {
+        tokio::select! {
+            Some(publish) = local_incoming.recv() => {
+                let Some(topic) = bridged_topic(&config.topics, &publish.topic, true) else {
+                    continue;
+                };
+                // Never wait for the remote broker, it would hold the built-in one back
+                match remote.client().try_publish(
+                    topic.clone(),
+                    publish.qos,
+                    publish.retain,
+                    publish.payload.clone(),
+                ) {
+                    Ok(()) => {
+                        // Only the topics subscribed on the remote broker come back
+                        if bridged_topic(&config.topics, &topic, false).is_some() {
+                            remote_echoes.forwarded(&topic, &publish.payload);
+                        }
+                    }
+                    Err(e) => debug!("Bridged message on '{}' dropped: {}", topic, e),
+                }
+            }
+            Some(publish) = remote_incoming.recv() => {
+                if remote_echoes.is_echo(&publish.topic, &publish.payload) {
+                    continue;
+                }
+                let Some(topic) = bridged_topic(&config.topics, &publish.topic, false) else {
+                    continue;
+                };
+                let options = PublishOptions::new().qos(publish.qos).retain(publish.retain);
+                if let Err(e) = local
+                    .publish_with(topic.clone(), publish.payload.to_vec(), options)
+                    .await
+                {
+                    warn!("Unable to bridge message on '{}': {}", topic, e);
+                }
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Run the bridge of the broker configuration, if any, never returns
+///
+/// A bridge which stops is restarted, after a delay growing with the number of
+/// failures in a row.
+pub async fn run(config: Option<MqttBridgeConfig>, hub: Arc<LocalHub>) {
+    let Some(config) = config else {
+        return std::future::pending().await;
+    };
+    let policy = ReconnectPolicy::default();
+    let mut attempt = 0;
+    loop {
+        let (local, local_incoming) =
+            LocalClient::new_no_local(hub.clone(), QoS::AtLeastOnce, false, String::new());
+        let started = Instant::now();
+        match run_bridge(config.clone(), local, local_incoming).await {
+            Ok(()) => warn!("Bridge stopped"),
+            Err(e) => warn!("Bridge stopped: {}", e),
+        }
+        attempt = match started.elapsed() >= STABLE_RUN {
+            true => 1,
+            false => attempt + 1,
+        };
+        let delay = policy.delay(attempt);
+        info!("Bridge restarted in {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
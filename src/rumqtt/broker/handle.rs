@@ -0,0 +1,339 @@
+use super::local::LocalClient;
+use super::local::LocalHub;
+use super::local::Origin;
+use super::recorder::Recorder;
+use super::retained::RetainedStore;
+use super::stats::BrokerStats;
+use super::stats::BrokerStatsSnapshot;
+use super::stats::ClientStats;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// =============================================================================
+
+#[derive(Clone, Debug, Default)]
+/// Addresses the broker listeners are bound to
+///
+/// Ports are resolved, even for endpoints configured with port 0.
+pub struct BrokerAddrs {
+    /// TCP listener
+    pub tcp: Option<SocketAddr>,
+
+    /// WebSocket listener
+    pub websocket: Option<SocketAddr>,
+
+    /// MQTT over TLS listener
+    pub tls: Option<SocketAddr>,
+
+    /// WebSocket over TLS listener
+    pub wss: Option<SocketAddr>,
+
+    /// TCP listener for MQTT v5 clients
+    pub tcp_v5: Option<SocketAddr>,
+
+    /// Named listeners, by name
+    pub named: BTreeMap<String, SocketAddr>,
+}
+
+// =============================================================================
+
+/// Handle on a running MQTT broker
+///
+/// Awaiting the handle waits for the broker to stop and returns its final
+/// result. Dropping the handle stops the broker.
+///
+/// Stopping the broker closes its public listeners and disconnects all its
+/// clients. The rumqttd core has no way to be stopped: its router threads and
+/// loopback listeners stay in the process, unreachable, until the process
+/// exits.
+pub struct BrokerHandle {
+    /// Shutdown signal shared with the broker listeners
+    shutdown: watch::Sender<bool>,
+
+    /// Final result of the broker, sent once all its listeners are closed
+    finished: Option<oneshot::Receiver<anyhow::Result<()>>>,
+
+    /// Thread hosting the broker listeners, if not served on the caller runtime
+    thread: Option<JoinHandle<()>>,
+
+    /// Bound addresses of the listeners
+    addrs: BrokerAddrs,
+
+    /// Statistics of the broker, if enabled in its configuration
+    stats: Option<Arc<BrokerStats>>,
+
+    /// Link of the in-process clients
+    local: Arc<LocalHub>,
+
+    /// Persistence of the retained messages, if enabled in the configuration
+    retained: Option<Arc<RetainedStore>>,
+
+    /// Recording of the published messages, if enabled in the configuration
+    recorder: Option<Arc<Recorder>>,
+}
+
+// =============================================================================
+
+impl BrokerHandle {
+    /// Create a new handle from the shutdown signal, the final result channel,
+    /// the bound addresses of the listeners and the link of the local clients
+    pub(crate) fn new(
+        shutdown: watch::Sender<bool>,
+        finished: oneshot::Receiver<anyhow::Result<()>>,
+        addrs: BrokerAddrs,
+        local: Arc<LocalHub>,
+    ) -> Self {
+        Self {
+            shutdown,
+            finished: Some(finished),
+            thread: None,
+            addrs,
+            stats: None,
+            local,
+            retained: None,
+            recorder: None,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Attach the thread hosting the broker listeners
+    pub(crate) fn with_thread(mut self, thread: JoinHandle<()>) -> Self {
+        self.thread = Some(thread);
+        self
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Attach the statistics collected by the broker listeners
+    pub(crate) fn with_stats(mut self, stats: Option<Arc<BrokerStats>>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Attach the persistence of the retained messages
+    pub(crate) fn with_retained(mut self, retained: Option<Arc<RetainedStore>>) -> Self {
+        self.retained = retained;
+        self
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Attach the recording of the published messages
+    pub(crate) fn with_recorder(mut self, recorder: Option<Arc<Recorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Address the TCP listener is bound to, with the port resolved if the
+    /// configured one was 0
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        self.addrs.tcp
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Address the WebSocket listener is bound to, with the port resolved if
+    /// the configured one was 0
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.addrs.websocket
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Address a named listener is bound to, with the port resolved if the
+    /// configured one was 0
+    pub fn listener_addr(&self, name: &str) -> Option<SocketAddr> {
+        self.addrs.named.get(name).copied()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Addresses all the listeners are bound to
+    pub fn addrs(&self) -> &BrokerAddrs {
+        &self.addrs
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Statistics of the broker, `None` if not enabled in its configuration
+    pub fn stats(&self) -> Option<BrokerStatsSnapshot> {
+        self.stats.as_ref().map(|stats| stats.snapshot())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Connected clients, empty if the statistics are not enabled
+    pub fn clients(&self) -> Vec<ClientStats> {
+        self.stats()
+            .map(|snapshot| snapshot.clients)
+            .unwrap_or_default()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Create an in-process client with specified QoS and retain settings
+    ///
+    /// The client does not go through any socket and stays usable while the
+    /// broker runs. Its incoming messages are given to the returned receiver.
+    pub fn local_client<A: Into<String>>(
+        &self,
+        qos: QoS,
+        retain: bool,
+        prefix: A,
+    ) -> (LocalClient, mpsc::Receiver<Publish>) {
+        LocalClient::new(self.local.clone(), qos, retain, prefix.into())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Number of retained messages in the store, `None` if not enabled
+    pub fn retained_count(&self) -> Option<usize> {
+        self.retained.as_ref().map(|store| store.len())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Wipe the retained store and clear its messages from the broker
+    pub fn wipe_retained(&self) -> anyhow::Result<()> {
+        let store = self
+            .retained
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Broker retained store not enabled"))?;
+        for topic in store.wipe()? {
+            // An empty retained message clears the one of the broker
+            self.local
+                .publish(Origin::Broker, topic, QoS::AtMostOnce, true, Vec::new())?;
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Current capture file, `None` if the recorder is not enabled
+    ///
+    /// The recorded messages are flushed first, so the file can be read.
+    pub fn capture_file(&self) -> Option<PathBuf> {
+        self.recorder.as_ref().map(|recorder| {
+            recorder.flush();
+            recorder.file().to_path_buf()
+        })
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Stop the broker, see [`BrokerHandle`] for what is stopped
+    ///
+    /// Blocks until all the listener sockets are closed and returns the final
+    /// result of the broker. Use [`BrokerHandle::shutdown`] from async code.
+    pub fn stop(mut self) -> anyhow::Result<()> {
+        self.shutdown.send_replace(true);
+        self.join_blocking()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Wait until the broker stops by itself and return its final result
+    ///
+    /// Blocks the current thread, await the handle from async code.
+    pub fn join(mut self) -> anyhow::Result<()> {
+        self.join_blocking()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Stop the broker and wait until all the listener sockets are closed
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        self.shutdown.send_replace(true);
+        self.wait().await
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True while the broker is running
+    pub fn is_running(&self) -> bool {
+        // The listeners drop their receivers once closed
+        !self.shutdown.is_closed()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Wait for the final result of the broker
+    async fn wait(&mut self) -> anyhow::Result<()> {
+        let end_result = match self.finished.take() {
+            Some(finished) => finished
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Broker service aborted"))),
+            None => Ok(()),
+        };
+        if let Some(thread) = self.thread.take() {
+            // The result is sent just before the thread returns
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+        end_result
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Wait for the final result of the broker from a synchronous context
+    fn join_blocking(&mut self) -> anyhow::Result<()> {
+        let finished = match self.finished.take() {
+            Some(finished) => finished,
+            None => return Ok(()),
+        };
+        match self.thread.take() {
+            Some(thread) => {
+                thread
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("Broker thread panicked"))?;
+                let mut finished = finished;
+                finished
+                    .try_recv()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Broker service aborted")))
+            }
+            None => finished
+                .blocking_recv()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Broker service aborted"))),
+        }
+    }
+}
+
+// =============================================================================
+
+impl IntoFuture for BrokerHandle {
+    type Output = anyhow::Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    /// Resolve when the broker stops
+    fn into_future(mut self) -> Self::IntoFuture {
+        Box::pin(async move { self.wait().await })
+    }
+}
+
+// =============================================================================
+
+impl Drop for BrokerHandle {
+    /// Stop the broker when the handle goes out of scope
+    fn drop(&mut self) {
+        self.shutdown.send_replace(true);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
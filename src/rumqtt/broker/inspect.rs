@@ -0,0 +1,1117 @@
+use super::acl::Acl;
+use super::acl::ClientIdentity;
+use super::auth::Authenticator;
+use super::auth::RelaySecret;
+use super::recorder::Recorder;
+use super::stats::ConnectionStats;
+use bytes::Bytes;
+use bytes::BytesMut;
+use rumqttc::mqttbytes::check;
+use rumqttc::mqttbytes::v4::ConnAck;
+use rumqttc::mqttbytes::v4::Connect;
+use rumqttc::mqttbytes::v4::ConnectReturnCode;
+use rumqttc::mqttbytes::v4::Login;
+use rumqttc::mqttbytes::v4::PubAck;
+use rumqttc::mqttbytes::v4::PubComp;
+use rumqttc::mqttbytes::v4::PubRec;
+use rumqttc::mqttbytes::v4::PubRel;
+use rumqttc::mqttbytes::v4::Publish;
+use rumqttc::mqttbytes::v4::SubAck;
+use rumqttc::mqttbytes::v4::Subscribe;
+use rumqttc::mqttbytes::v4::SubscribeReasonCode;
+use rumqttc::mqttbytes::v4::Unsubscribe;
+use rumqttc::mqttbytes::Error as MqttError;
+use rumqttc::mqttbytes::FixedHeader;
+use rumqttc::mqttbytes::PacketType;
+use rumqttc::v5::mqttbytes::v5;
+use rumqttc::v5::mqttbytes::QoS as QoS5;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::debug;
+use tracing::warn;
+
+/// Largest remaining length allowed by MQTT, for the packets of the broker
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// Protocol level of MQTT v3.1.1 in the CONNECT packet
+const PROTOCOL_LEVEL_V4: u8 = 4;
+
+/// Protocol level of MQTT v5 in the CONNECT packet
+const PROTOCOL_LEVEL_V5: u8 = 5;
+
+// =============================================================================
+
+/// Packet level processing applied by the relay on plain MQTT connections
+///
+/// The relay decodes MQTT v3.1.1 and v5 packets, clients of other protocol
+/// versions are refused. Listeners carrying TLS or WebSocket cannot be
+/// inspected at all.
+pub struct Inspection {
+    /// Topic level ACLs
+    pub acl: Option<Acl>,
+
+    /// Credentials of the clients, checked by the relay as the core only
+    /// accepts the relay secret on inspected listeners
+    pub auth: Option<Arc<Authenticator>>,
+
+    /// Password given to the core in place of the one of the client
+    pub secret: RelaySecret,
+
+    /// Largest remaining length of a packet, the `max_payload_size` of the
+    /// listener, so oversized packets are refused before being buffered
+    pub max_packet_size: usize,
+
+    /// Capture the accepted publishes are recorded to
+    pub recorder: Option<Arc<Recorder>>,
+}
+
+// =============================================================================
+
+/// Protocol version of an inspected connection, known from its CONNECT packet
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    /// MQTT v3.1.1
+    #[default]
+    V4,
+    /// MQTT v5
+    V5,
+}
+
+// =============================================================================
+
+/// State of an inspected connection, shared by both directions
+#[derive(Default)]
+struct Session {
+    /// Protocol version of the client
+    protocol: Protocol,
+
+    /// Identity announced by the client
+    client: ClientIdentity,
+
+    /// Positions of the accepted filters of the SUBSCRIBE packets forwarded
+    /// with some filters removed, by packet id
+    partial_subscribes: HashMap<u16, Vec<bool>>,
+
+    /// Packet ids of the QoS 2 publishes rejected by the relay
+    rejected_qos2: HashSet<u16>,
+
+    /// Topics of the MQTT v5 topic aliases set by the client
+    topic_aliases: HashMap<u16, String>,
+
+    /// Statistics of the connection
+    stats: Option<ConnectionStats>,
+
+    /// Filters of the SUBSCRIBE packets forwarded to the broker, by packet id,
+    /// only tracked for the statistics
+    pending_subscribes: HashMap<u16, Vec<String>>,
+}
+
+// =============================================================================
+
+/// What to do with a packet received from the client
+enum Verdict {
+    /// Forward these bytes to the broker
+    Forward(Bytes),
+    /// Check the credentials of the client, then forward its CONNECT packet
+    Authenticate(PendingConnect),
+    /// Drop the packet
+    Discard,
+    /// Answer the client with these bytes instead of forwarding
+    Reply(Bytes),
+    /// Answer the client with these bytes and close the connection
+    Refuse(Bytes),
+}
+
+// =============================================================================
+
+/// CONNECT packet waiting for the credentials of the client to be checked
+struct PendingConnect {
+    /// Client ID, for the logs
+    client_id: String,
+
+    /// User name and password sent by the client
+    login: Option<(String, String)>,
+
+    /// CONNECT packet to forward, with the relay secret as password
+    forward: Bytes,
+
+    /// CONNACK refusing the client
+    refusal: Bytes,
+}
+
+// =============================================================================
+
+/// CONNECT packet decoded with the codec of its protocol version
+enum ConnectPacket {
+    /// MQTT v3.1.1
+    V4(Connect),
+    /// MQTT v5, with its will and credentials decoded apart
+    V5(Box<(v5::Connect, Option<v5::LastWill>, Option<v5::Login>)>),
+}
+
+// -----------------------------------------------------------------------------
+
+impl ConnectPacket {
+    /// Protocol version of the packet
+    fn protocol(&self) -> Protocol {
+        match self {
+            Self::V4(_) => Protocol::V4,
+            Self::V5(_) => Protocol::V5,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Identity announced by the client
+    fn identity(&self) -> ClientIdentity {
+        let (client_id, username) = match self {
+            Self::V4(connect) => (
+                &connect.client_id,
+                connect.login.as_ref().map(|login| &login.username),
+            ),
+            Self::V5(packet) => (
+                &packet.0.client_id,
+                packet.2.as_ref().map(|login| &login.username),
+            ),
+        };
+        ClientIdentity {
+            client_id: client_id.clone(),
+            username: username.cloned(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// User name and password sent by the client
+    fn login(&self) -> Option<(String, String)> {
+        match self {
+            Self::V4(connect) => connect
+                .login
+                .as_ref()
+                .map(|login| (login.username.clone(), login.password.clone())),
+            Self::V5(packet) => packet
+                .2
+                .as_ref()
+                .map(|login| (login.username.clone(), login.password.clone())),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Topic of the will of the client
+    fn will_topic(&self) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::V4(connect) => Ok(connect.last_will.as_ref().map(|will| will.topic.clone())),
+            Self::V5(packet) => match &packet.1 {
+                Some(will) => Ok(Some(std::str::from_utf8(&will.topic)?.to_string())),
+                None => Ok(None),
+            },
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// CONNACK refusing the client with this reason
+    fn refusal(&self, code: ConnectReturnCode) -> anyhow::Result<Bytes> {
+        match self {
+            Self::V4(_) => {
+                let connack = ConnAck::new(code, false);
+                encode(|b| connack.write(b))
+            }
+            Self::V5(_) => {
+                let code = match code {
+                    ConnectReturnCode::BadUserNamePassword => {
+                        v5::ConnectReturnCode::BadUserNamePassword
+                    }
+                    _ => v5::ConnectReturnCode::NotAuthorized,
+                };
+                let connack = v5::ConnAck {
+                    session_present: false,
+                    code,
+                    properties: None,
+                };
+                encode(|b| connack.write(b))
+            }
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Encode the packet with the relay secret as password, keeping the user
+    /// name of the client
+    fn with_secret(self, secret: &RelaySecret) -> anyhow::Result<Bytes> {
+        let username = self.identity().username.unwrap_or_default();
+        let password = secret.as_str().to_string();
+        match self {
+            Self::V4(mut connect) => {
+                connect.login = Some(Login { username, password });
+                encode(|b| connect.write(b))
+            }
+            Self::V5(packet) => {
+                let (connect, will, _) = *packet;
+                let login = Some(v5::Login { username, password });
+                encode(|b| connect.write(&will, &login, b))
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Pipe a client connection to the rumqttd listener, inspecting MQTT packets
+pub async fn forward(
+    client: TcpStream,
+    upstream: SocketAddr,
+    inspection: Arc<Inspection>,
+    stats: Option<ConnectionStats>,
+) {
+    let broker = match TcpStream::connect(upstream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Unable to reach broker core on {}: {}", upstream, e);
+            return;
+        }
+    };
+    let _ = client.set_nodelay(true);
+    let _ = broker.set_nodelay(true);
+
+    let (client_rd, client_wr) = client.into_split();
+    let (broker_rd, broker_wr) = broker.into_split();
+    let (reply_tx, reply_rx) = mpsc::unbounded_channel();
+    let session = Mutex::new(Session {
+        stats,
+        ..Default::default()
+    });
+
+    // The first direction to end closes the whole connection
+    let end_result = tokio::select! {
+        r = client_to_broker(client_rd, broker_wr, reply_tx, &session, &inspection) => r,
+        r = broker_to_client(broker_rd, client_wr, reply_rx, &session) => r,
+    };
+    if let Err(e) = end_result {
+        debug!("Connection closed: {}", e);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Extract the next complete packet from the buffer
+fn next_frame(
+    buffer: &mut BytesMut,
+    max_packet_size: usize,
+) -> anyhow::Result<Option<(FixedHeader, Bytes)>> {
+    match check(buffer.iter(), max_packet_size) {
+        Ok(header) => {
+            let frame = buffer.split_to(header.frame_length()).freeze();
+            Ok(Some((header, frame)))
+        }
+        Err(MqttError::InsufficientBytes(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Forward client packets to the broker
+async fn client_to_broker<R, W>(
+    mut client_rd: R,
+    mut broker_wr: W,
+    reply_tx: mpsc::UnboundedSender<Bytes>,
+    session: &Mutex<Session>,
+    inspection: &Inspection,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        while let Some((header, frame)) = next_frame(&mut buffer, inspection.max_packet_size)? {
+            let verdict = {
+                let mut session = session.lock().unwrap();
+                inspect_client_packet(header, frame, &mut session, inspection)?
+            };
+            match verdict {
+                Verdict::Forward(bytes) => broker_wr.write_all(&bytes).await?,
+                Verdict::Authenticate(connect) => {
+                    if !authenticate(&connect, inspection).await? {
+                        return refuse(connect.refusal, reply_tx).await;
+                    }
+                    broker_wr.write_all(&connect.forward).await?
+                }
+                Verdict::Discard => {}
+                Verdict::Reply(bytes) => reply_tx.send(bytes)?,
+                Verdict::Refuse(bytes) => return refuse(bytes, reply_tx).await,
+            }
+        }
+        if client_rd.read_buf(&mut buffer).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Answer the client with these bytes, then let the other direction close the
+/// connection once they are written
+async fn refuse(bytes: Bytes, reply_tx: mpsc::UnboundedSender<Bytes>) -> anyhow::Result<()> {
+    reply_tx.send(bytes)?;
+    drop(reply_tx);
+    std::future::pending().await
+}
+
+// -----------------------------------------------------------------------------
+
+/// Check the credentials of a connecting client
+///
+/// Password hashes are slow to verify on purpose, so the check runs outside
+/// of the async workers.
+async fn authenticate(connect: &PendingConnect, inspection: &Inspection) -> anyhow::Result<bool> {
+    let Some(authenticator) = inspection.auth.clone() else {
+        return Ok(true);
+    };
+    let Some((username, password)) = connect.login.clone() else {
+        warn!(
+            "Connection refused for client '{}' without credentials",
+            connect.client_id
+        );
+        return Ok(false);
+    };
+    let client_id = connect.client_id.clone();
+    let granted =
+        tokio::task::spawn_blocking(move || authenticator.check(&client_id, &username, &password))
+            .await?;
+    Ok(granted)
+}
+
+// -----------------------------------------------------------------------------
+
+/// Forward broker packets and relay replies to the client
+async fn broker_to_client<R, W>(
+    mut broker_rd: R,
+    mut client_wr: W,
+    mut reply_rx: mpsc::UnboundedReceiver<Bytes>,
+    session: &Mutex<Session>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        while let Some((header, frame)) = next_frame(&mut buffer, MAX_REMAINING_LENGTH)? {
+            let bytes = {
+                let mut session = session.lock().unwrap();
+                inspect_broker_packet(header, frame, &mut session)?
+            };
+            client_wr.write_all(&bytes).await?;
+        }
+        tokio::select! {
+            read = broker_rd.read_buf(&mut buffer) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+            reply = reply_rx.recv() => match reply {
+                Some(bytes) => client_wr.write_all(&bytes).await?,
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Decide what to do with a packet sent by the client
+fn inspect_client_packet(
+    header: FixedHeader,
+    frame: Bytes,
+    session: &mut Session,
+    inspection: &Inspection,
+) -> anyhow::Result<Verdict> {
+    let packet_type = header.packet_type()?;
+    if let Some(stats) = &session.stats {
+        stats.received(frame.len(), packet_type == PacketType::Publish);
+    }
+
+    match (packet_type, session.protocol) {
+        (PacketType::Connect, _) => inspect_connect(header, frame, session, inspection),
+        (PacketType::Publish, Protocol::V4) => {
+            let publish = Publish::read(header, frame.clone())?;
+            if let Some(acl) = &inspection.acl {
+                if !acl.can_publish(&session.client, &publish.topic) {
+                    return reject_publish(&publish, session);
+                }
+            }
+            if !publish.dup {
+                record_publish(
+                    inspection,
+                    session,
+                    &publish.topic,
+                    publish.qos as u8,
+                    publish.retain,
+                    &publish.payload,
+                );
+            }
+            Ok(Verdict::Forward(frame))
+        }
+        (PacketType::Publish, Protocol::V5) => {
+            let publish = v5::Publish::read(header_v5(&frame)?, frame.clone())?;
+            let topic = publish_topic_v5(&publish, session)?;
+            if let Some(acl) = &inspection.acl {
+                if !acl.can_publish(&session.client, &topic) {
+                    return reject_publish_v5(&publish);
+                }
+            }
+            if !publish.dup {
+                record_publish(
+                    inspection,
+                    session,
+                    &topic,
+                    publish.qos as u8,
+                    publish.retain,
+                    &publish.payload,
+                );
+            }
+            Ok(Verdict::Forward(frame))
+        }
+        (PacketType::PubRel, Protocol::V4) => {
+            let pubrel = PubRel::read(header, frame.clone())?;
+            if session.rejected_qos2.remove(&pubrel.pkid) {
+                let pubcomp = PubComp::new(pubrel.pkid);
+                return Ok(Verdict::Reply(encode(|b| pubcomp.write(b))?));
+            }
+            Ok(Verdict::Forward(frame))
+        }
+        (PacketType::Subscribe, Protocol::V4) => {
+            let subscribe = Subscribe::read(header, frame.clone())?;
+            let filters = subscribe.filters.iter().map(|filter| &filter.path);
+            let accepted = accept_filters(subscribe.pkid, filters, session, inspection);
+            filter_subscribe(subscribe, accepted, frame, session)
+        }
+        (PacketType::Subscribe, Protocol::V5) => {
+            let subscribe = v5::Subscribe::read(header_v5(&frame)?, frame.clone())?;
+            let filters = subscribe.filters.iter().map(|filter| &filter.path);
+            let accepted = accept_filters(subscribe.pkid, filters, session, inspection);
+            filter_subscribe_v5(subscribe, accepted, frame, session)
+        }
+        (PacketType::Unsubscribe, Protocol::V4) => {
+            if let Some(stats) = &session.stats {
+                let unsubscribe = Unsubscribe::read(header, frame.clone())?;
+                stats.unsubscribed(&unsubscribe.topics);
+            }
+            Ok(Verdict::Forward(frame))
+        }
+        (PacketType::Unsubscribe, Protocol::V5) => {
+            if let Some(stats) = &session.stats {
+                let unsubscribe = v5::Unsubscribe::read(header_v5(&frame)?, frame.clone())?;
+                stats.unsubscribed(&unsubscribe.filters);
+            }
+            Ok(Verdict::Forward(frame))
+        }
+        _ => Ok(Verdict::Forward(frame)),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Record a publish accepted from a client, with its own QoS
+///
+/// Retransmissions, flagged as duplicates, were recorded when first read.
+fn record_publish(
+    inspection: &Inspection,
+    session: &Session,
+    topic: &str,
+    qos: u8,
+    retain: bool,
+    payload: &[u8],
+) {
+    if let Some(recorder) = &inspection.recorder {
+        recorder.record(&session.client.client_id, topic, qos, retain, payload);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Identify the client from its CONNECT packet, which reaches the core with
+/// the relay secret as password
+fn inspect_connect(
+    header: FixedHeader,
+    frame: Bytes,
+    session: &mut Session,
+    inspection: &Inspection,
+) -> anyhow::Result<Verdict> {
+    // Each protocol version has its own codec, the level is read before the
+    // rest of the packet
+    let level = protocol_level(&frame);
+    let connect = match level {
+        Some(PROTOCOL_LEVEL_V4) => ConnectPacket::V4(Connect::read(header, frame)?),
+        Some(PROTOCOL_LEVEL_V5) => {
+            ConnectPacket::V5(Box::new(v5::Connect::read(header_v5(&frame)?, frame)?))
+        }
+        _ => {
+            warn!(
+                "Client with MQTT protocol level {:?} refused, this listener inspects \
+                 packets and only accepts MQTT v3.1.1 and v5",
+                level
+            );
+            let connack = ConnAck::new(ConnectReturnCode::RefusedProtocolVersion, false);
+            return Ok(Verdict::Refuse(encode(|b| connack.write(b))?));
+        }
+    };
+
+    session.protocol = connect.protocol();
+    session.client = connect.identity();
+    if let Some(stats) = &session.stats {
+        stats.identify(
+            &session.client.client_id,
+            session.client.username.as_deref(),
+        );
+    }
+    // The will is published on behalf of the client, under its ACL
+    if let (Some(acl), Some(topic)) = (&inspection.acl, connect.will_topic()?) {
+        if !acl.can_publish(&session.client, &topic) {
+            let refusal = connect.refusal(ConnectReturnCode::NotAuthorized)?;
+            return Ok(Verdict::Refuse(refusal));
+        }
+    }
+
+    let pending = PendingConnect {
+        client_id: session.client.client_id.clone(),
+        login: connect.login(),
+        refusal: connect.refusal(ConnectReturnCode::BadUserNamePassword)?,
+        forward: connect.with_secret(&inspection.secret)?,
+    };
+    match inspection.auth {
+        Some(_) => Ok(Verdict::Authenticate(pending)),
+        None => Ok(Verdict::Forward(pending.forward)),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Topic of an MQTT v5 publish, resolved from its topic alias if empty
+fn publish_topic_v5(publish: &v5::Publish, session: &mut Session) -> anyhow::Result<String> {
+    let topic = std::str::from_utf8(&publish.topic)?.to_string();
+    let alias = publish
+        .properties
+        .as_ref()
+        .and_then(|properties| properties.topic_alias);
+    match alias {
+        Some(alias) if topic.is_empty() => session
+            .topic_aliases
+            .get(&alias)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Publish with unknown topic alias {}", alias)),
+        Some(alias) => {
+            session.topic_aliases.insert(alias, topic.clone());
+            Ok(topic)
+        }
+        None => Ok(topic),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Check the filters of a SUBSCRIBE packet against the ACL, and track the
+/// accepted ones for the statistics
+fn accept_filters<'a>(
+    pkid: u16,
+    filters: impl Iterator<Item = &'a String>,
+    session: &mut Session,
+    inspection: &Inspection,
+) -> Vec<bool> {
+    let mut accepted = Vec::new();
+    let mut forwarded = Vec::new();
+    for filter in filters {
+        let accept = match &inspection.acl {
+            Some(acl) => acl.can_subscribe(&session.client, filter),
+            None => true,
+        };
+        if accept {
+            forwarded.push(filter.clone());
+        }
+        accepted.push(accept);
+    }
+    if session.stats.is_some() && !forwarded.is_empty() {
+        session.pending_subscribes.insert(pkid, forwarded);
+    }
+    accepted
+}
+
+// -----------------------------------------------------------------------------
+
+/// Acknowledge a rejected publish without forwarding it
+///
+/// Like most brokers, the rejection is silent for MQTT v3.1.1 clients.
+fn reject_publish(publish: &Publish, session: &mut Session) -> anyhow::Result<Verdict> {
+    match publish.qos {
+        rumqttc::QoS::AtMostOnce => Ok(Verdict::Discard),
+        rumqttc::QoS::AtLeastOnce => {
+            let puback = PubAck::new(publish.pkid);
+            Ok(Verdict::Reply(encode(|b| puback.write(b))?))
+        }
+        rumqttc::QoS::ExactlyOnce => {
+            session.rejected_qos2.insert(publish.pkid);
+            let pubrec = PubRec::new(publish.pkid);
+            Ok(Verdict::Reply(encode(|b| pubrec.write(b))?))
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Acknowledge a rejected MQTT v5 publish with a "Not authorized" reason
+///
+/// An error reason ends the QoS 2 exchange at the PUBREC.
+fn reject_publish_v5(publish: &v5::Publish) -> anyhow::Result<Verdict> {
+    match publish.qos {
+        QoS5::AtMostOnce => Ok(Verdict::Discard),
+        QoS5::AtLeastOnce => {
+            let puback = v5::PubAck {
+                pkid: publish.pkid,
+                reason: v5::PubAckReason::NotAuthorized,
+                properties: None,
+            };
+            Ok(Verdict::Reply(encode(|b| puback.write(b))?))
+        }
+        QoS5::ExactlyOnce => {
+            let pubrec = v5::PubRec {
+                pkid: publish.pkid,
+                reason: v5::PubRecReason::NotAuthorized,
+                properties: None,
+            };
+            Ok(Verdict::Reply(encode(|b| pubrec.write(b))?))
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Remove the rejected filters from a SUBSCRIBE packet
+fn filter_subscribe(
+    mut subscribe: Subscribe,
+    accepted: Vec<bool>,
+    frame: Bytes,
+    session: &mut Session,
+) -> anyhow::Result<Verdict> {
+    // Nothing rejected
+    if accepted.iter().all(|accepted| *accepted) {
+        return Ok(Verdict::Forward(frame));
+    }
+
+    // Everything rejected, the broker is not involved
+    if accepted.iter().all(|accepted| !*accepted) {
+        let suback = SubAck::new(
+            subscribe.pkid,
+            vec![SubscribeReasonCode::Failure; accepted.len()],
+        );
+        return Ok(Verdict::Reply(encode(|b| suback.write(b))?));
+    }
+
+    // Forward the accepted filters only, the SUBACK is completed on its way back
+    let mut position = 0;
+    subscribe.filters.retain(|_| {
+        position += 1;
+        accepted[position - 1]
+    });
+    session.partial_subscribes.insert(subscribe.pkid, accepted);
+    Ok(Verdict::Forward(encode(|b| subscribe.write(b))?))
+}
+
+// -----------------------------------------------------------------------------
+
+/// Remove the rejected filters from an MQTT v5 SUBSCRIBE packet
+fn filter_subscribe_v5(
+    mut subscribe: v5::Subscribe,
+    accepted: Vec<bool>,
+    frame: Bytes,
+    session: &mut Session,
+) -> anyhow::Result<Verdict> {
+    // Nothing rejected
+    if accepted.iter().all(|accepted| *accepted) {
+        return Ok(Verdict::Forward(frame));
+    }
+
+    // Everything rejected, the broker is not involved
+    if accepted.iter().all(|accepted| !*accepted) {
+        let suback = v5::SubAck {
+            pkid: subscribe.pkid,
+            return_codes: vec![v5::SubscribeReasonCode::NotAuthorized; accepted.len()],
+            properties: None,
+        };
+        return Ok(Verdict::Reply(encode(|b| suback.write(b))?));
+    }
+
+    // Forward the accepted filters only, the SUBACK is completed on its way back
+    let mut position = 0;
+    subscribe.filters.retain(|_| {
+        position += 1;
+        accepted[position - 1]
+    });
+    session.partial_subscribes.insert(subscribe.pkid, accepted);
+    Ok(Verdict::Forward(encode(|b| subscribe.write(b))?))
+}
+
+// -----------------------------------------------------------------------------
+
+/// Process a packet sent by the broker
+fn inspect_broker_packet(
+    header: FixedHeader,
+    frame: Bytes,
+    session: &mut Session,
+) -> anyhow::Result<Bytes> {
+    let packet_type = header.packet_type()?;
+    if let Some(stats) = &session.stats {
+        stats.sent(frame.len(), packet_type == PacketType::Publish);
+    }
+    if packet_type != PacketType::SubAck
+        || (session.partial_subscribes.is_empty() && session.pending_subscribes.is_empty())
+    {
+        return Ok(frame);
+    }
+
+    match session.protocol {
+        Protocol::V4 => {
+            let suback = SubAck::read(header, frame.clone())?;
+            let granted = suback
+                .return_codes
+                .iter()
+                .map(|code| *code != SubscribeReasonCode::Failure);
+            record_granted(suback.pkid, granted, session);
+            match session.partial_subscribes.remove(&suback.pkid) {
+                Some(accepted) => {
+                    // Insert a failure for each filter removed by the relay
+                    let return_codes = complete_codes(
+                        &accepted,
+                        suback.return_codes,
+                        SubscribeReasonCode::Failure,
+                    );
+                    let suback = SubAck::new(suback.pkid, return_codes);
+                    encode(|b| suback.write(b))
+                }
+                None => Ok(frame),
+            }
+        }
+        Protocol::V5 => {
+            let mut suback = v5::SubAck::read(header_v5(&frame)?, frame.clone())?;
+            let granted = suback
+                .return_codes
+                .iter()
+                .map(|code| matches!(code, v5::SubscribeReasonCode::Success(_)));
+            record_granted(suback.pkid, granted, session);
+            match session.partial_subscribes.remove(&suback.pkid) {
+                Some(accepted) => {
+                    // Insert a refusal for each filter removed by the relay
+                    suback.return_codes = complete_codes(
+                        &accepted,
+                        suback.return_codes,
+                        v5::SubscribeReasonCode::NotAuthorized,
+                    );
+                    encode(|b| suback.write(b))
+                }
+                None => Ok(frame),
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Add the filters granted by the broker to the statistics
+fn record_granted(pkid: u16, granted: impl Iterator<Item = bool>, session: &mut Session) {
+    if let Some(filters) = session.pending_subscribes.remove(&pkid) {
+        // The codes of the broker only cover the forwarded filters
+        let granted = filters
+            .into_iter()
+            .zip(granted)
+            .filter(|(_, granted)| *granted)
+            .map(|(filter, _)| filter)
+            .collect();
+        if let Some(stats) = &session.stats {
+            stats.subscribed(granted);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Codes of a SUBACK for all the filters of the client, from the codes of the
+/// broker for the forwarded ones
+fn complete_codes<C: Copy>(accepted: &[bool], broker_codes: Vec<C>, refused: C) -> Vec<C> {
+    let mut codes = broker_codes.into_iter();
+    accepted
+        .iter()
+        .map(|accepted| match accepted {
+            true => codes.next().unwrap_or(refused),
+            false => refused,
+        })
+        .collect()
+}
+
+// -----------------------------------------------------------------------------
+
+/// Protocol level of a CONNECT packet, `None` if the packet is malformed
+fn protocol_level(frame: &Bytes) -> Option<u8> {
+    // Fixed header: a byte then the remaining length, whose last byte has its
+    // high bit cleared
+    let length_bytes = frame.get(1..)?.iter().position(|b| b & 0x80 == 0)? + 1;
+    // Then the protocol name as a length prefixed string
+    let variable_header = frame.get(1 + length_bytes..)?;
+    let name_len = u16::from_be_bytes([*variable_header.first()?, *variable_header.get(1)?]);
+    variable_header.get(2 + name_len as usize).copied()
+}
+
+// -----------------------------------------------------------------------------
+
+/// Header of a frame for the MQTT v5 codec
+fn header_v5(frame: &Bytes) -> anyhow::Result<v5::FixedHeader> {
+    Ok(v5::check(frame.iter(), None)?)
+}
+
+// -----------------------------------------------------------------------------
+
+/// Encode a packet into bytes
+fn encode<F, E>(write: F) -> anyhow::Result<Bytes>
+where
+    F: FnOnce(&mut BytesMut) -> Result<usize, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut buffer = BytesMut::new();
+    write(&mut buffer)?;
+    Ok(buffer.freeze())
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MqttAclAction;
+    use crate::config::MqttAclConfig;
+    use crate::config::MqttAclPermission;
+    use crate::config::MqttAclRuleConfig;
+    use crate::config::MqttRecorderConfig;
+    use crate::rumqtt::broker::recorder::read_capture;
+
+    // -------------------------------------------------------------------------
+
+    /// Inspection denying publications and subscriptions under `secret/`
+    fn inspection() -> Inspection {
+        Inspection {
+            acl: Some(Acl::new(&MqttAclConfig {
+                default_permission: None,
+                rules: Some(vec![MqttAclRuleConfig {
+                    users: None,
+                    client_ids: None,
+                    prefix: None,
+                    action: MqttAclAction::All,
+                    permission: MqttAclPermission::Deny,
+                    topics: vec!["secret/#".to_string()],
+                }]),
+            })),
+            auth: None,
+            secret: RelaySecret::generate(),
+            max_packet_size: MAX_REMAINING_LENGTH,
+            recorder: None,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Give an encoded packet sent by the client to the relay
+    fn client_packet(
+        bytes: Bytes,
+        session: &mut Session,
+        inspection: &Inspection,
+    ) -> anyhow::Result<Verdict> {
+        let mut buffer = BytesMut::from(&bytes[..]);
+        let (header, frame) = next_frame(&mut buffer, MAX_REMAINING_LENGTH)?.unwrap();
+        inspect_client_packet(header, frame, session, inspection)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Give an encoded packet sent by the broker to the relay
+    fn broker_packet(bytes: Bytes, session: &mut Session) -> anyhow::Result<Bytes> {
+        let mut buffer = BytesMut::from(&bytes[..]);
+        let (header, frame) = next_frame(&mut buffer, MAX_REMAINING_LENGTH)?.unwrap();
+        inspect_broker_packet(header, frame, session)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// MQTT v5 CONNECT packet with credentials
+    fn connect_v5(client_id: &str) -> Bytes {
+        let connect = v5::Connect {
+            keep_alive: 30,
+            client_id: client_id.to_string(),
+            clean_start: true,
+            properties: None,
+        };
+        let login = Some(v5::Login::new("alice", "a-pass"));
+        encode(|b| connect.write(&None, &login, b)).unwrap()
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn core_receives_the_relay_secret_instead_of_the_password() {
+        let inspection = inspection();
+        let mut session = Session::default();
+
+        let mut connect = Connect::new("psu-1");
+        connect.login = Some(Login {
+            username: "alice".to_string(),
+            password: "a-pass".to_string(),
+        });
+        let bytes = encode(|b| connect.write(b)).unwrap();
+        let Verdict::Forward(forwarded) = client_packet(bytes, &mut session, &inspection).unwrap()
+        else {
+            panic!("CONNECT not forwarded");
+        };
+        let mut buffer = BytesMut::from(&forwarded[..]);
+        let (header, frame) = next_frame(&mut buffer, MAX_REMAINING_LENGTH)
+            .unwrap()
+            .unwrap();
+        let login = Connect::read(header, frame).unwrap().login.unwrap();
+        assert_eq!(login.username, "alice");
+        assert_eq!(login.password, inspection.secret.as_str());
+        assert_eq!(session.client.username.as_deref(), Some("alice"));
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn v5_publishes_are_checked_through_their_topic_alias() {
+        let inspection = inspection();
+        let mut session = Session::default();
+        let verdict = client_packet(connect_v5("psu-1"), &mut session, &inspection).unwrap();
+        assert!(matches!(verdict, Verdict::Forward(_)));
+        assert!(session.protocol == Protocol::V5);
+
+        // The alias is set by the first publish, then used with an empty topic
+        let properties = v5::PublishProperties {
+            topic_alias: Some(1),
+            ..Default::default()
+        };
+        let mut publish = v5::Publish::new("secret/key", QoS5::AtLeastOnce, "x", None);
+        publish.pkid = 1;
+        publish.properties = Some(properties.clone());
+        let bytes = encode(|b| publish.write(b)).unwrap();
+        let Verdict::Reply(reply) = client_packet(bytes, &mut session, &inspection).unwrap() else {
+            panic!("publish not rejected");
+        };
+        let puback = v5::PubAck::read(header_v5(&reply).unwrap(), reply).unwrap();
+        assert_eq!(puback.reason, v5::PubAckReason::NotAuthorized);
+
+        let publish = v5::Publish::new("", QoS5::AtMostOnce, "x", Some(properties));
+        let bytes = encode(|b| publish.write(b)).unwrap();
+        let verdict = client_packet(bytes, &mut session, &inspection).unwrap();
+        assert!(matches!(verdict, Verdict::Discard));
+
+        let publish = v5::Publish::new("pza/psu/att", QoS5::AtMostOnce, "x", None);
+        let bytes = encode(|b| publish.write(b)).unwrap();
+        let verdict = client_packet(bytes, &mut session, &inspection).unwrap();
+        assert!(matches!(verdict, Verdict::Forward(_)));
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn v5_subacks_are_completed_with_refused_filters() {
+        let inspection = inspection();
+        let mut session = Session::default();
+        client_packet(connect_v5("scope-1"), &mut session, &inspection).unwrap();
+
+        let mut subscribe = v5::Subscribe::new_many(
+            [
+                v5::Filter::new("pza/#", QoS5::AtLeastOnce),
+                v5::Filter::new("secret/key", QoS5::AtLeastOnce),
+            ],
+            None,
+        );
+        subscribe.pkid = 7;
+        let bytes = encode(|b| subscribe.write(b)).unwrap();
+        let Verdict::Forward(forwarded) = client_packet(bytes, &mut session, &inspection).unwrap()
+        else {
+            panic!("SUBSCRIBE not forwarded");
+        };
+        let forwarded = v5::Subscribe::read(header_v5(&forwarded).unwrap(), forwarded).unwrap();
+        assert_eq!(forwarded.filters.len(), 1);
+        assert_eq!(forwarded.filters[0].path, "pza/#");
+
+        // The broker only answers for the forwarded filter
+        let suback = v5::SubAck {
+            pkid: 7,
+            return_codes: vec![v5::SubscribeReasonCode::Success(QoS5::AtLeastOnce)],
+            properties: None,
+        };
+        let bytes = encode(|b| suback.write(b)).unwrap();
+        let completed = broker_packet(bytes, &mut session).unwrap();
+        let completed = v5::SubAck::read(header_v5(&completed).unwrap(), completed).unwrap();
+        assert_eq!(
+            completed.return_codes,
+            [
+                v5::SubscribeReasonCode::Success(QoS5::AtLeastOnce),
+                v5::SubscribeReasonCode::NotAuthorized,
+            ]
+        );
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn accepted_publishes_are_recorded_with_their_client_and_qos() {
+        let file = std::env::temp_dir().join(format!(
+            "pza-toolkit-relay-capture-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        let config = MqttRecorderConfig {
+            file: Some(file.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let inspection = Inspection {
+            recorder: Some(Arc::new(Recorder::open(&config).unwrap())),
+            ..inspection()
+        };
+        let mut session = Session::default();
+        let connect = encode(|b| Connect::new("psu-1").write(b)).unwrap();
+        client_packet(connect, &mut session, &inspection).unwrap();
+
+        let mut publish = Publish::new("pza/psu/volts", rumqttc::QoS::ExactlyOnce, "3.3");
+        publish.pkid = 1;
+        publish.retain = true;
+        let bytes = encode(|b| publish.write(b)).unwrap();
+        client_packet(bytes, &mut session, &inspection).unwrap();
+
+        // Neither the retransmission nor the refused publish are recorded
+        publish.dup = true;
+        let bytes = encode(|b| publish.write(b)).unwrap();
+        client_packet(bytes, &mut session, &inspection).unwrap();
+        let mut refused = Publish::new("secret/key", rumqttc::QoS::AtLeastOnce, "x");
+        refused.pkid = 2;
+        let bytes = encode(|b| refused.write(b)).unwrap();
+        client_packet(bytes, &mut session, &inspection).unwrap();
+
+        inspection.recorder.as_ref().unwrap().flush();
+        let messages = read_capture(&file).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].client_id, "psu-1");
+        assert_eq!(messages[0].topic, "pza/psu/volts");
+        assert_eq!(messages[0].qos, 2);
+        assert!(messages[0].retain);
+        assert_eq!(messages[0].payload, b"3.3");
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,792 @@
+use super::acl::Acl;
+use super::acl::ClientIdentity;
+use super::recorder::Recorder;
+use super::retained::RetainedStore;
+use crate::rumqtt::client::SubscribeError;
+use crate::rumqtt::client::SubscribeFailure;
+use crate::rumqtt::dispatch::TopicDispatcher;
+use crate::rumqtt::json::JsonError;
+use crate::rumqtt::publish::PublishOptions;
+use crate::rumqtt::publish::PublishPolicy;
+use crate::rumqtt::topic::filter_matches;
+use crate::rumqtt::topic::is_valid_filter;
+use bytes::Bytes;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use rumqttd::local::LinkRx;
+use rumqttd::local::LinkTx;
+use rumqttd::protocol::Packet;
+use rumqttd::Notification;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::warn;
+
+/// Client id of the link shared by the local clients, and prefix of their own
+/// client ids
+pub const LOCAL_LINK_ID: &str = "local-clients";
+
+/// Capacity of the incoming message channel of each local client
+const INCOMING_CAPACITY: usize = 100;
+
+/// Maximum total size of the retained payloads kept for the local
+/// subscriptions when the broker has no retained store
+const RETAINED_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+// =============================================================================
+
+/// Error of a local client
+#[derive(Debug, thiserror::Error)]
+pub enum LocalClientError {
+    /// The topic filter is not a valid MQTT filter
+    #[error("invalid topic filter '{0}'")]
+    InvalidFilter(String),
+
+    /// The broker ACL does not allow the operation on this topic or filter
+    #[error("'{0}' not allowed by the broker ACL")]
+    Denied(String),
+
+    /// The broker core refused the request, usually because it is stopped
+    #[error("broker link closed: {0}")]
+    Link(String),
+}
+
+// =============================================================================
+
+/// Local client registered on the hub
+struct LocalSubscriber {
+    /// Id of the client
+    id: u64,
+
+    /// Filters the client is subscribed to
+    filters: Vec<String>,
+
+    /// True if the client does not receive its own messages
+    no_local: bool,
+
+    /// Channel of the incoming messages of the client
+    incoming: mpsc::Sender<Publish>,
+}
+
+// =============================================================================
+
+/// Publisher of a message pushed on the link
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Origin {
+    /// The broker itself, restoring or wiping its retained messages
+    Broker,
+
+    /// A local client, by id
+    Client(u64),
+}
+
+// =============================================================================
+
+/// Retained messages kept for the local subscriptions, when the broker has no
+/// retained store to read them from
+#[derive(Default)]
+struct RetainedMessages {
+    /// Messages by topic
+    messages: BTreeMap<String, Publish>,
+
+    /// Total size of the payloads
+    bytes: usize,
+}
+
+// -----------------------------------------------------------------------------
+
+impl RetainedMessages {
+    /// Keep a retained message, an empty payload clears the topic
+    ///
+    /// Messages which would exceed the size cap are not kept.
+    fn retain(&mut self, publish: &Publish) {
+        let previous = self
+            .messages
+            .get(&publish.topic)
+            .map(|message| message.payload.len())
+            .unwrap_or(0);
+        if publish.payload.is_empty() {
+            self.messages.remove(&publish.topic);
+            self.bytes -= previous;
+            return;
+        }
+        let bytes = self.bytes - previous + publish.payload.len();
+        if bytes > RETAINED_MAX_BYTES {
+            warn!(
+                "Retained messages of the local clients full, message on '{}' not kept",
+                publish.topic
+            );
+            return;
+        }
+        self.messages.insert(publish.topic.clone(), publish.clone());
+        self.bytes = bytes;
+    }
+}
+
+// =============================================================================
+
+/// Link of the broker core shared by all the local clients
+///
+/// rumqttd only creates links before its core is started, so a single link is
+/// opened at startup. It is subscribed once to every topic, keeping the retain
+/// flag of the publishes and without its own messages, so the core only gives
+/// it the messages of the network clients. They are dispatched to the local
+/// clients by topic filter, while the messages of the local clients are
+/// dispatched by the hub as they are published. The retained messages are
+/// read from the retained store, or kept by the hub without one, so each new
+/// subscription of a local client receives the matching ones.
+///
+/// Every message of the local clients is recorded with the client id of its
+/// publisher. The messages of the link come from the network clients, already
+/// recorded by the relay, and the internal messages of the broker are not
+/// recorded.
+pub struct LocalHub {
+    /// Sending half of the link
+    tx: Mutex<LinkTx>,
+
+    /// Registered local clients
+    subscribers: Mutex<Vec<LocalSubscriber>>,
+
+    /// Filters starting with `$` subscribed on the link, `#` does not match
+    /// their topics
+    linked: Mutex<HashSet<String>>,
+
+    /// Retained messages of the broker, only without retained store
+    retained_messages: Mutex<RetainedMessages>,
+
+    /// Id of the next local client
+    next_id: AtomicU64,
+
+    /// Last packet id used for QoS 1 and 2 publishes
+    last_pkid: AtomicU16,
+
+    /// Store the retained messages of the broker are copied to
+    retained: Option<Arc<RetainedStore>>,
+
+    /// Capture the published messages are recorded to
+    recorder: Option<Arc<Recorder>>,
+
+    /// Topic level ACLs applied to the local clients
+    acl: Option<Acl>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl LocalHub {
+    /// Create the hub from the sending half of its link
+    pub fn new(
+        tx: LinkTx,
+        retained: Option<Arc<RetainedStore>>,
+        recorder: Option<Arc<Recorder>>,
+        acl: Option<Acl>,
+    ) -> Self {
+        Self {
+            tx: Mutex::new(tx),
+            subscribers: Mutex::new(Vec::new()),
+            linked: Mutex::new(HashSet::new()),
+            retained_messages: Mutex::new(RetainedMessages::default()),
+            next_id: AtomicU64::new(0),
+            last_pkid: AtomicU16::new(0),
+            retained,
+            recorder,
+            acl,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Subscribe the link to every topic, with the retain flag as published and
+    /// without the messages of the link itself
+    ///
+    /// The subscription is at least once, so the QoS of the network publishes
+    /// is known up to 1.
+    pub(crate) fn subscribe_all(&self) -> Result<(), LocalClientError> {
+        self.link_subscribe("#")
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Subscribe the link to a filter, without the messages of the link itself
+    fn link_subscribe(&self, filter: &str) -> Result<(), LocalClientError> {
+        let subscribe = rumqttd::protocol::Subscribe {
+            pkid: self.next_pkid(),
+            filters: vec![rumqttd::protocol::Filter {
+                path: filter.to_string(),
+                qos: rumqttd::protocol::QoS::AtLeastOnce,
+                nolocal: true,
+                preserve_retain: true,
+                retain_forward_rule: rumqttd::protocol::RetainForwardRule::OnEverySubscribe,
+            }],
+        };
+        self.tx
+            .lock()
+            .unwrap()
+            .push(Packet::Subscribe(subscribe, None))
+            .map_err(|e| LocalClientError::Link(format!("{:?}", e)))?;
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Register a new local client and return its incoming message channel
+    ///
+    /// With `no_local`, the messages published by the client are not given
+    /// back to it.
+    fn register(&self, no_local: bool) -> (u64, mpsc::Receiver<Publish>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (incoming, incoming_rx) = mpsc::channel(INCOMING_CAPACITY);
+        self.subscribers.lock().unwrap().push(LocalSubscriber {
+            id,
+            filters: Vec::new(),
+            no_local,
+            incoming,
+        });
+        (id, incoming_rx)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Subscribe a local client to a filter and give it the matching retained
+    /// messages
+    async fn subscribe(&self, id: u64, filter: String) -> Result<(), LocalClientError> {
+        if !is_valid_filter(&filter) {
+            return Err(LocalClientError::InvalidFilter(filter));
+        }
+        if let Some(acl) = &self.acl {
+            if !acl.can_subscribe(&local_identity(id), &filter) {
+                return Err(LocalClientError::Denied(filter));
+            }
+        }
+        if filter.starts_with('$') && self.linked.lock().unwrap().insert(filter.clone()) {
+            if let Err(e) = self.link_subscribe(&filter) {
+                self.linked.lock().unwrap().remove(&filter);
+                return Err(e);
+            }
+        }
+
+        let incoming = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let Some(subscriber) = subscribers.iter_mut().find(|s| s.id == id) else {
+                return Ok(());
+            };
+            if !subscriber.filters.contains(&filter) {
+                subscriber.filters.push(filter.clone());
+            }
+            subscriber.incoming.clone()
+        };
+        // Only the subscribing client waits for its receiver
+        for message in self.retained_matching(&filter) {
+            let _ = incoming.send(message).await;
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Retained messages matching a filter, flagged as retained
+    fn retained_matching(&self, filter: &str) -> Vec<Publish> {
+        match &self.retained {
+            Some(store) => store
+                .messages()
+                .into_iter()
+                .filter(|message| filter_matches(filter, &message.topic))
+                .map(|message| {
+                    let qos = message.qos();
+                    let mut publish = Publish::new(message.topic, qos, message.payload);
+                    publish.retain = true;
+                    publish
+                })
+                .collect(),
+            None => self
+                .retained_messages
+                .lock()
+                .unwrap()
+                .messages
+                .values()
+                .filter(|message| filter_matches(filter, &message.topic))
+                .cloned()
+                .map(|mut publish| {
+                    publish.retain = true;
+                    publish
+                })
+                .collect(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Unsubscribe a local client from a filter
+    ///
+    /// A `$` filter no other local client uses is also removed from the link.
+    fn unsubscribe(&self, id: u64, filter: &str) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscriber) = subscribers.iter_mut().find(|s| s.id == id) {
+            subscriber.filters.retain(|f| f != filter);
+        }
+        self.unlink_unused(&subscribers);
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Remove from the link the `$` filters no local client uses anymore
+    fn unlink_unused(&self, subscribers: &[LocalSubscriber]) {
+        let mut linked = self.linked.lock().unwrap();
+        let unused: Vec<String> = linked
+            .iter()
+            .filter(|filter| {
+                !subscribers
+                    .iter()
+                    .any(|subscriber| subscriber.filters.contains(filter))
+            })
+            .cloned()
+            .collect();
+        if unused.is_empty() {
+            return;
+        }
+        for filter in &unused {
+            linked.remove(filter);
+        }
+        let unsubscribe = rumqttd::protocol::Unsubscribe {
+            pkid: self.next_pkid(),
+            filters: unused,
+        };
+        if let Err(e) = self
+            .tx
+            .lock()
+            .unwrap()
+            .push(Packet::Unsubscribe(unsubscribe, None))
+        {
+            warn!("Unable to unsubscribe local client link: {:?}", e);
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Publish a message through the link and give it to the local clients
+    pub(crate) fn publish(
+        &self,
+        origin: Origin,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), LocalClientError> {
+        let (core_qos, pkid) = match qos {
+            QoS::AtMostOnce => (rumqttd::protocol::QoS::AtMostOnce, 0),
+            QoS::AtLeastOnce => (rumqttd::protocol::QoS::AtLeastOnce, self.next_pkid()),
+            QoS::ExactlyOnce => (rumqttd::protocol::QoS::ExactlyOnce, self.next_pkid()),
+        };
+        let payload = Bytes::from(payload);
+        let publish = rumqttd::protocol::Publish {
+            dup: false,
+            qos: core_qos,
+            retain,
+            topic: topic.clone().into(),
+            pkid,
+            payload: payload.clone(),
+        };
+        self.tx
+            .lock()
+            .unwrap()
+            .push(Packet::Publish(publish, None))
+            .map_err(|e| LocalClientError::Link(format!("{:?}", e)))?;
+
+        // The core does not give the messages of the link back to it
+        let message = Publish::new(topic, qos, payload);
+        self.deliver(message, retain, Some(origin));
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Publish a message of a local client, under the ACL
+    fn publish_as_client(
+        &self,
+        id: u64,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), LocalClientError> {
+        if let Some(acl) = &self.acl {
+            if !acl.can_publish(&local_identity(id), &topic) {
+                return Err(LocalClientError::Denied(topic));
+            }
+        }
+        self.publish(Origin::Client(id), topic, qos, retain, payload)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Acknowledge a message forwarded to the link at least once
+    fn ack(&self, pkid: u16) {
+        let ack = rumqttd::protocol::PubAck {
+            pkid,
+            reason: rumqttd::protocol::PubAckReason::Success,
+        };
+        if let Err(e) = self.tx.lock().unwrap().push(Packet::PubAck(ack, None)) {
+            warn!(
+                "Unable to acknowledge message on local client link: {:?}",
+                e
+            );
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Next packet id, 0 is not a valid one
+    fn next_pkid(&self) -> u16 {
+        loop {
+            let pkid = self
+                .last_pkid
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1);
+            if pkid != 0 {
+                return pkid;
+            }
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Keep a retained message for the next subscriptions, in the store if
+    /// any, an empty payload clears the topic
+    fn retain(&self, publish: &Publish) {
+        match &self.retained {
+            Some(store) => store.record(&publish.topic, &publish.payload, publish.qos as u8),
+            None => self.retained_messages.lock().unwrap().retain(publish),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record, retain and dispatch a message of a local client, of the broker
+    /// or of the link
+    ///
+    /// Retained messages are only flagged as such when given on subscription,
+    /// like a broker does for its clients.
+    fn deliver(&self, mut message: Publish, retain: bool, origin: Option<Origin>) {
+        self.record(&message, retain, origin);
+        if retain {
+            message.retain = true;
+            self.retain(&message);
+            message.retain = false;
+        }
+        self.dispatch(message, origin);
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record a message of a local client in the capture, those of the
+    /// network clients are recorded by the relay
+    fn record(&self, publish: &Publish, retain: bool, origin: Option<Origin>) {
+        let (Some(recorder), Some(Origin::Client(id))) = (&self.recorder, origin) else {
+            return;
+        };
+        recorder.record(
+            &local_client_id(id),
+            &publish.topic,
+            publish.qos as u8,
+            retain,
+            &publish.payload,
+        );
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Give a message to every local client subscribed to its topic, except
+    /// its publisher if it does not want its own messages
+    ///
+    /// Never waits for a client: a message is dropped for a client whose
+    /// receiver is full.
+    fn dispatch(&self, publish: Publish, origin: Option<Origin>) {
+        let targets: Vec<(u64, mpsc::Sender<Publish>)> = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            // Clients whose receiver is dropped are gone
+            let count = subscribers.len();
+            subscribers.retain(|subscriber| !subscriber.incoming.is_closed());
+            if subscribers.len() != count {
+                self.unlink_unused(&subscribers);
+            }
+            subscribers
+                .iter()
+                .filter(|subscriber| {
+                    !(subscriber.no_local && origin == Some(Origin::Client(subscriber.id)))
+                })
+                .filter(|subscriber| {
+                    subscriber
+                        .filters
+                        .iter()
+                        .any(|filter| filter_matches(filter, &publish.topic))
+                })
+                .map(|subscriber| (subscriber.id, subscriber.incoming.clone()))
+                .collect()
+        };
+        for (id, target) in targets {
+            if let Err(TrySendError::Full(publish)) = target.try_send(publish.clone()) {
+                warn!(
+                    "Message on '{}' dropped for local client '{}', its receiver is too slow",
+                    publish.topic,
+                    local_client_id(id)
+                );
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Client id of a local client, for the ACL and the recorder
+fn local_client_id(id: u64) -> String {
+    format!("{}-{}", LOCAL_LINK_ID, id)
+}
+
+// -----------------------------------------------------------------------------
+
+/// Identity of a local client for the ACL
+fn local_identity(id: u64) -> ClientIdentity {
+    ClientIdentity {
+        client_id: local_client_id(id),
+        username: None,
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Dispatch the messages of the network clients to the local clients, never
+/// returns
+pub async fn run(hub: Arc<LocalHub>, mut rx: LinkRx) {
+    loop {
+        match rx.next().await {
+            Ok(Some(Notification::Forward(forward)))
+            | Ok(Some(Notification::ForwardWithProperties(forward, _))) => {
+                let publish = forward.publish;
+                if publish.qos != rumqttd::protocol::QoS::AtMostOnce {
+                    hub.ack(publish.pkid);
+                }
+                let topic = match String::from_utf8(publish.topic.to_vec()) {
+                    Ok(topic) => topic,
+                    Err(_) => continue,
+                };
+                let qos = match publish.qos {
+                    rumqttd::protocol::QoS::AtMostOnce => QoS::AtMostOnce,
+                    rumqttd::protocol::QoS::AtLeastOnce => QoS::AtLeastOnce,
+                    rumqttd::protocol::QoS::ExactlyOnce => QoS::ExactlyOnce,
+                };
+                let message = Publish::new(topic, qos, publish.payload);
+                hub.deliver(message, publish.retain, None);
+            }
+            // Acknowledgements of the link
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Local client link closed: {:?}", e);
+                return std::future::pending().await;
+            }
+        }
+    }
+}
+
+// =============================================================================
+
+/// In-process client of the embedded broker, without any socket
+///
+/// Offers the publish and subscribe methods of `RumqttCustomAsyncClient`. The
+/// incoming messages are given to the receiver returned with the client, which
+/// can be run by a [`TopicDispatcher`]. All the local clients share one
+/// connection to the broker core: a message is only received once by each
+/// client, and each subscription receives the matching retained messages. A
+/// message is dropped for a client whose receiver is full, so a slow client
+/// never holds the others back.
+///
+/// Each client has its own client id, `local-clients-<n>`, which the ACL rules
+/// and the recorder see.
+#[derive(Clone)]
+pub struct LocalClient {
+    /// Link shared by the local clients
+    hub: Arc<LocalHub>,
+
+    /// Id of the client on the hub
+    id: u64,
+
+    /// Quality of Service level for MQTT messages
+    pub qos: QoS,
+
+    /// Retain flag for MQTT messages
+    pub retain: bool,
+
+    /// Prefix of the topics of the client
+    pub prefix: String,
+
+    /// QoS and retain settings by topic, overriding the predefined ones
+    pub policy: PublishPolicy,
+}
+
+// -----------------------------------------------------------------------------
+
+impl LocalClient {
+    /// Create a new local client with specified QoS and retain settings
+    pub fn new(
+        hub: Arc<LocalHub>,
+        qos: QoS,
+        retain: bool,
+        prefix: String,
+    ) -> (Self, mpsc::Receiver<Publish>) {
+        Self::register(hub, qos, retain, prefix, false)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Create a local client which does not receive its own messages
+    pub(crate) fn new_no_local(
+        hub: Arc<LocalHub>,
+        qos: QoS,
+        retain: bool,
+        prefix: String,
+    ) -> (Self, mpsc::Receiver<Publish>) {
+        Self::register(hub, qos, retain, prefix, true)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Register a new client on the hub
+    fn register(
+        hub: Arc<LocalHub>,
+        qos: QoS,
+        retain: bool,
+        prefix: String,
+        no_local: bool,
+    ) -> (Self, mpsc::Receiver<Publish>) {
+        let (id, incoming) = hub.register(no_local);
+        let client = Self {
+            hub,
+            id,
+            qos,
+            retain,
+            prefix,
+            policy: PublishPolicy::default(),
+        };
+        (client, incoming)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Client id of the client, for the ACL rules and in the recordings
+    pub fn client_id(&self) -> String {
+        local_client_id(self.id)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Use a policy to choose the QoS and retain settings of each topic
+    pub fn with_policy(mut self, policy: PublishPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Subscribe to a topic filter
+    pub async fn subscribe<A: Into<String>>(&self, filter: A) -> Result<(), LocalClientError> {
+        self.hub.subscribe(self.id, filter.into()).await
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Stop receiving the messages of a topic filter
+    pub async fn unsubscribe<A: AsRef<str>>(&self, filter: A) -> Result<(), LocalClientError> {
+        self.hub.unsubscribe(self.id, filter.as_ref());
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Subscribe to all relevant MQTT topics
+    ///
+    /// Every topic is tried, the invalid ones are listed in the error.
+    pub async fn subscribe_to_all(&self, topics: Vec<String>) -> Result<(), SubscribeError> {
+        let mut failures = Vec::new();
+        for topic in topics {
+            if let Err(e) = self.hub.subscribe(self.id, topic.clone()).await {
+                warn!("Unable to subscribe to '{}': {}", topic, e);
+                failures.push((topic, SubscribeFailure::NotSent));
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(SubscribeError { failures }),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Publish a message to a topic using the predefined QoS and retain settings,
+    /// or the ones of the policy for this topic
+    pub async fn publish<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+    ) -> Result<(), LocalClientError> {
+        self.publish_with(topic, payload, PublishOptions::default())
+            .await
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Publish a message with options overriding the policy and predefined settings
+    pub async fn publish_with<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+        options: PublishOptions,
+    ) -> Result<(), LocalClientError> {
+        let topic = topic.into();
+        let (qos, retain) = self.resolve_options(&topic, options);
+        self.hub
+            .publish_as_client(self.id, topic, qos, retain, payload.into())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// QoS and retain flag of a publish, from the options, then the policy and
+    /// then the predefined settings
+    pub fn resolve_options(&self, topic: &str, options: PublishOptions) -> (QoS, bool) {
+        let options = options.or(self.policy.options_for(&self.prefix, topic));
+        (
+            options.qos.unwrap_or(self.qos),
+            options.retain.unwrap_or(self.retain),
+        )
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Publish a value serialized as JSON, using the predefined QoS and retain settings
+    pub async fn publish_json<A: Into<String>, T: serde::Serialize>(
+        &self,
+        topic: A,
+        value: &T,
+    ) -> Result<(), JsonError> {
+        let payload = serde_json::to_vec(value).map_err(JsonError::Serialize)?;
+        self.publish(topic, payload).await?;
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Generate a topic string with the configured prefix
+    pub fn topic_with_prefix<A: AsRef<str>>(&self, topic: A) -> String {
+        format!("{}/{}", self.prefix, topic.as_ref())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Create a dispatcher whose filters are relative to the configured prefix
+    pub fn dispatcher(&self) -> TopicDispatcher {
+        TopicDispatcher::new(self.prefix.clone())
+    }
+}
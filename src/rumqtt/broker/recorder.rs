@@ -0,0 +1,516 @@
+use crate::config::MqttRecorderConfig;
+use crate::rumqtt::client::RumqttCustomAsyncClient;
+use crate::rumqtt::publish::PublishOptions;
+use crate::rumqtt::util::now_ms;
+use crate::rumqtt::util::qos_from_level;
+use crate::rumqtt::util::JsonPayload;
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::info;
+use tracing::warn;
+
+/// Size from which the capture file is rotated when not configured
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated files kept when not configured
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// Interval between two flushes of the capture file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// =============================================================================
+
+/// Message published through the broker
+///
+/// In the capture file, a UTF-8 payload is written as text in `payload`, any
+/// other one in base64 in `payload_base64`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "CaptureLine", try_from = "CaptureLine")]
+pub struct CapturedMessage {
+    /// Time of the publish, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+
+    /// Id of the publishing client, `local-clients-<n>` for the local ones
+    pub client_id: String,
+
+    /// Topic of the message
+    pub topic: String,
+
+    /// Quality of service, as its MQTT level
+    pub qos: u8,
+
+    /// Retain flag of the message
+    pub retain: bool,
+
+    /// Payload of the message
+    pub payload: Vec<u8>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl CapturedMessage {
+    /// Quality of service, invalid levels fall back to at least once
+    pub fn qos(&self) -> QoS {
+        qos_from_level(self.qos)
+    }
+}
+
+// =============================================================================
+
+/// Line of the capture file
+#[derive(Serialize, Deserialize)]
+struct CaptureLine {
+    /// Time of the publish, in milliseconds since the Unix epoch
+    timestamp_ms: u64,
+
+    /// Id of the publishing client, empty in the captures of older versions
+    #[serde(default)]
+    client_id: String,
+
+    /// Topic of the message
+    topic: String,
+
+    /// Quality of service, as its MQTT level
+    qos: u8,
+
+    /// Retain flag of the message
+    retain: bool,
+
+    /// Payload of the message
+    #[serde(flatten)]
+    payload: JsonPayload,
+}
+
+// -----------------------------------------------------------------------------
+
+impl From<CapturedMessage> for CaptureLine {
+    fn from(message: CapturedMessage) -> Self {
+        Self {
+            timestamp_ms: message.timestamp_ms,
+            client_id: message.client_id,
+            topic: message.topic,
+            qos: message.qos,
+            retain: message.retain,
+            payload: message.payload.into(),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+impl TryFrom<CaptureLine> for CapturedMessage {
+    type Error = String;
+
+    fn try_from(line: CaptureLine) -> Result<Self, Self::Error> {
+        let payload = line.payload.try_into()?;
+        Ok(Self {
+            timestamp_ms: line.timestamp_ms,
+            client_id: line.client_id,
+            topic: line.topic,
+            qos: line.qos,
+            retain: line.retain,
+            payload,
+        })
+    }
+}
+
+// =============================================================================
+
+/// Open capture file and its size
+struct RecorderState {
+    /// Buffered capture file
+    writer: BufWriter<File>,
+
+    /// Size of the capture file, including the buffered lines
+    bytes: u64,
+}
+
+// =============================================================================
+
+/// Capture of the messages published through the broker
+///
+/// Each message is a JSON line, see [`CapturedMessage`]. The relay records the
+/// messages of the network clients as it reads them, the local hub those of
+/// the local clients. Once the file reaches its maximum size, it is renamed
+/// with a `.1` suffix, the older ones shifted up to `max_files`.
+pub struct Recorder {
+    /// Current capture file
+    file: PathBuf,
+
+    /// Size from which the capture file is rotated
+    max_bytes: u64,
+
+    /// Number of rotated files kept
+    max_files: usize,
+
+    /// Open capture file
+    state: Mutex<RecorderState>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl Recorder {
+    /// Open the capture file, new messages are appended to it
+    pub fn open(config: &MqttRecorderConfig) -> anyhow::Result<Self> {
+        let file = config
+            .resolved_file()
+            .ok_or_else(|| anyhow::anyhow!("Unable to determine home directory"))?;
+        let state = Mutex::new(open_capture(&file)?);
+        info!("Broker messages recorded to {}", file.display());
+        Ok(Self {
+            file,
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_files: config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            state,
+        })
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Append a published message to the capture
+    ///
+    /// Failures are logged, the broker keeps running without the message.
+    pub fn record(&self, client_id: &str, topic: &str, qos: u8, retain: bool, payload: &[u8]) {
+        let message = CapturedMessage {
+            timestamp_ms: now_ms(),
+            client_id: client_id.to_string(),
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload: payload.to_vec(),
+        };
+        let mut line = match serde_json::to_vec(&message) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Unable to record message on '{}': {}", topic, e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut state = self.state.lock().unwrap();
+        if state.bytes > 0 && state.bytes + line.len() as u64 > self.max_bytes {
+            if let Err(e) = self.rotate(&mut state) {
+                warn!(
+                    "Unable to rotate capture file {}: {}",
+                    self.file.display(),
+                    e
+                );
+            }
+        }
+        match state.writer.write_all(&line) {
+            Ok(()) => state.bytes += line.len() as u64,
+            Err(e) => warn!("Unable to record message on '{}': {}", topic, e),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Write the buffered messages into the capture file
+    pub fn flush(&self) {
+        if let Err(e) = self.state.lock().unwrap().writer.flush() {
+            warn!(
+                "Unable to flush capture file {}: {}",
+                self.file.display(),
+                e
+            );
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Current capture file
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Shift the rotated files and start a new capture file
+    fn rotate(&self, state: &mut RecorderState) -> anyhow::Result<()> {
+        state.writer.flush()?;
+        let rotated = |index: usize| {
+            let mut name = self.file.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.file)?;
+        } else {
+            let oldest = rotated(self.max_files);
+            if oldest.exists() {
+                std::fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.max_files).rev() {
+                let from = rotated(index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.file, rotated(1))?;
+        }
+        *state = open_capture(&self.file)?;
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Open a capture file in append mode, creating its directory
+fn open_capture(file: &Path) -> anyhow::Result<RecorderState> {
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let handle = OpenOptions::new().create(true).append(true).open(file)?;
+    let bytes = handle.metadata()?.len();
+    Ok(RecorderState {
+        writer: BufWriter::new(handle),
+        bytes,
+    })
+}
+
+// -----------------------------------------------------------------------------
+
+/// Flush the capture file periodically, never returns
+pub async fn run(recorder: Option<Arc<Recorder>>) {
+    let Some(recorder) = recorder else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        recorder.flush();
+    }
+}
+
+// =============================================================================
+
+/// Read the messages of a capture file, in recording order
+///
+/// Malformed lines, like the last one of a broker stopped while writing, are
+/// skipped.
+pub fn read_capture<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<CapturedMessage>> {
+    let content = std::fs::read_to_string(path.as_ref())?;
+    let mut messages = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(message) => messages.push(message),
+            Err(e) => warn!(
+                "Line {} of {} skipped: {}",
+                number + 1,
+                path.as_ref().display(),
+                e
+            ),
+        }
+    }
+    Ok(messages)
+}
+
+// -----------------------------------------------------------------------------
+
+/// Publish captured messages again, keeping their topic, QoS and retain flag
+///
+/// The delays between the messages are the recorded ones divided by `speed`:
+/// 1.0 replays at the original speed, 2.0 twice as fast.
+pub async fn replay(
+    client: &RumqttCustomAsyncClient,
+    messages: &[CapturedMessage],
+    speed: f64,
+) -> anyhow::Result<()> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err(anyhow::anyhow!("Invalid replay speed {}", speed));
+    }
+    let Some(first) = messages.first() else {
+        return Ok(());
+    };
+
+    let start = tokio::time::Instant::now();
+    for message in messages {
+        let offset_ms = message.timestamp_ms.saturating_sub(first.timestamp_ms);
+        let offset = Duration::from_secs_f64(offset_ms as f64 / 1000.0 / speed);
+        tokio::time::sleep_until(start + offset).await;
+        let options = PublishOptions::new()
+            .qos(message.qos())
+            .retain(message.retain);
+        client
+            .publish_with(message.topic.clone(), message.payload.clone(), options)
+            .await?;
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+/// Publish the messages of a capture file again, see [`replay`]
+pub async fn replay_file<P: AsRef<Path>>(
+    client: &RumqttCustomAsyncClient,
+    path: P,
+    speed: f64,
+) -> anyhow::Result<()> {
+    let messages = read_capture(path)?;
+    info!("Replaying {} captured messages", messages.len());
+    replay(client, &messages, speed).await
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::AsyncClient;
+    use rumqttc::MqttOptions;
+
+    // -------------------------------------------------------------------------
+
+    /// Recorder of a test, its files removed beforehand
+    fn recorder(name: &str, max_files: usize) -> Recorder {
+        let file = std::env::temp_dir().join(format!(
+            "pza-toolkit-capture-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        for index in 0..=max_files + 1 {
+            let mut path = file.clone().into_os_string();
+            if index > 0 {
+                path.push(format!(".{}", index));
+            }
+            let _ = std::fs::remove_file(path);
+        }
+        let config = MqttRecorderConfig {
+            file: Some(file.to_string_lossy().to_string()),
+            max_bytes: Some(1),
+            max_files: Some(max_files),
+        };
+        Recorder::open(&config).unwrap()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Payloads of the messages of a capture file
+    fn payloads<P: AsRef<Path>>(path: P) -> Vec<Vec<u8>> {
+        read_capture(path)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.payload)
+            .collect()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Captured message published at a time
+    fn message(timestamp_ms: u64) -> CapturedMessage {
+        CapturedMessage {
+            timestamp_ms,
+            client_id: "psu-1".to_string(),
+            topic: "pza/psu/volts".to_string(),
+            qos: 2,
+            retain: false,
+            payload: b"3.3".to_vec(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn payloads_are_written_as_text_or_base64() {
+        let text = message(1);
+        let line = serde_json::to_value(&text).unwrap();
+        assert_eq!(line["payload"], "3.3");
+        assert!(line.get("payload_base64").is_none());
+        let read: CapturedMessage = serde_json::from_value(line).unwrap();
+        assert_eq!(read.client_id, "psu-1");
+        assert_eq!(read.qos, 2);
+        assert_eq!(read.payload, b"3.3");
+
+        let binary = CapturedMessage {
+            payload: vec![0xff, 0x00, 0x10],
+            ..message(2)
+        };
+        let line = serde_json::to_value(&binary).unwrap();
+        assert!(line.get("payload").is_none());
+        assert!(line["payload_base64"].is_string());
+        let read: CapturedMessage = serde_json::from_value(line).unwrap();
+        assert_eq!(read.payload, [0xff, 0x00, 0x10]);
+
+        // Lines of older captures have no client id
+        let read: CapturedMessage = serde_json::from_str(
+            r#"{"timestamp_ms":3,"topic":"a","qos":1,"retain":true,"payload":"x"}"#,
+        )
+        .unwrap();
+        assert_eq!(read.client_id, "");
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn rotated_files_are_shifted_up_to_max_files() {
+        let recorder = recorder("rotate", 2);
+        for payload in ["1", "2", "3", "4"] {
+            recorder.record("psu-1", "pza/psu/volts", 1, false, payload.as_bytes());
+        }
+        recorder.flush();
+
+        let rotated = |index: usize| {
+            let mut name = recorder.file().to_path_buf().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+        assert_eq!(payloads(recorder.file()), [b"4"]);
+        assert_eq!(payloads(rotated(1)), [b"3"]);
+        assert_eq!(payloads(rotated(2)), [b"2"]);
+        assert!(!rotated(3).exists());
+        for index in 1..=2 {
+            std::fs::remove_file(rotated(index)).unwrap();
+        }
+        std::fs::remove_file(recorder.file()).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn no_rotated_file_is_kept_without_max_files() {
+        let recorder = recorder("no-rotation", 0);
+        recorder.record("psu-1", "pza/psu/volts", 1, false, b"1");
+        recorder.record("psu-1", "pza/psu/volts", 1, false, b"2");
+        recorder.flush();
+
+        assert_eq!(payloads(recorder.file()), [b"2"]);
+        let mut rotated = recorder.file().to_path_buf().into_os_string();
+        rotated.push(".1");
+        assert!(!Path::new(&rotated).exists());
+        std::fs::remove_file(recorder.file()).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn replay_delays_are_divided_by_the_speed() {
+        // The event loop is never polled, the publishes stay in its channel
+        let (client, _event_loop) =
+            AsyncClient::new(MqttOptions::new("test-replay", "127.0.0.1", 1883), 10);
+        let client = RumqttCustomAsyncClient::new(client, QoS::AtLeastOnce, false, "pza".into());
+        let messages = [message(1_000), message(1_200), message(1_400)];
+
+        let start = std::time::Instant::now();
+        replay(&client, &messages, 4.0).await.unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_millis(300));
+
+        assert!(replay(&client, &messages, 0.0).await.is_err());
+        assert!(replay(&client, &messages, f64::NAN).await.is_err());
+    }
+}
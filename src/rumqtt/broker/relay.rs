@@ -0,0 +1,333 @@
+use super::inspect;
+use super::inspect::Inspection;
+use super::stats::BrokerStats;
+use super::stats::ConnectionStats;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// Time given to a freed loopback port to answer if someone else listens on it
+const RELEASE_CHECK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Size of the buffer of each direction of a forwarded connection
+const PIPE_BUFFER_SIZE: usize = 8 * 1024;
+
+// =============================================================================
+
+/// Public listener of the broker
+///
+/// rumqttd does not provide any way to close its listeners, so the toolkit owns
+/// the public sockets and forwards each client connection to a rumqttd
+/// listener bound on the loopback interface. Bytes are forwarded as is, so TLS
+/// is still terminated by rumqttd, unless an inspection is attached to the
+/// listener.
+pub struct RelayListener {
+    /// Name of the listener, used in logs
+    pub name: String,
+
+    /// Socket accepting the client connections
+    pub listener: std::net::TcpListener,
+
+    /// Address the public socket is bound to
+    pub local_addr: SocketAddr,
+
+    /// Loopback address of the matching rumqttd listener
+    pub upstream: SocketAddr,
+
+    /// MQTT packet inspection, only for plain MQTT listeners
+    pub inspection: Option<Arc<Inspection>>,
+
+    /// Statistics the connections are recorded in
+    pub stats: Option<Arc<BrokerStats>>,
+}
+
+// =============================================================================
+
+impl RelayListener {
+    /// Bind the public socket of a listener
+    ///
+    /// Port 0 binds on a free port chosen by the system, see `local_addr`.
+    pub fn bind<A: Into<String>>(
+        name: A,
+        listen_addr: &str,
+        upstream: SocketAddr,
+    ) -> anyhow::Result<Self> {
+        let listener = std::net::TcpListener::bind(listen_addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        Ok(Self {
+            name: name.into(),
+            listener,
+            local_addr,
+            upstream,
+            inspection: None,
+            stats: None,
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Loopback ports reserved for rumqttd listeners not bound by their core yet
+static CLAIMED_UPSTREAM_PORTS: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+// -----------------------------------------------------------------------------
+
+/// Loopback ports reserved for the rumqttd listeners of a broker
+///
+/// rumqttd binds its listeners itself, from the ports written in its
+/// configuration. The reserved sockets are kept bound until the core is about
+/// to start, so the system does not give the ports to anyone else, and the
+/// ports stay claimed until dropped, so they are not given twice to the
+/// brokers of the same process while their core binds them.
+#[derive(Default)]
+pub struct UpstreamPorts {
+    /// Sockets holding the reserved ports, until released
+    held: Vec<std::net::TcpListener>,
+
+    /// Reserved ports, claimed in the whole process
+    ports: Vec<u16>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl UpstreamPorts {
+    /// Reserve a free port on the loopback interface for a rumqttd listener
+    pub fn reserve(&mut self) -> std::io::Result<SocketAddr> {
+        let mut claimed = CLAIMED_UPSTREAM_PORTS.lock().unwrap();
+        // Ports of previous brokers are kept bound while looking for a new one,
+        // so the system does not return them again
+        let mut rejected = Vec::new();
+        loop {
+            let socket = std::net::TcpListener::bind("127.0.0.1:0")?;
+            let addr = socket.local_addr()?;
+            if claimed.insert(addr.port()) {
+                self.held.push(socket);
+                self.ports.push(addr.port());
+                return Ok(addr);
+            }
+            rejected.push(socket);
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Free the reserved ports for rumqttd, they stay claimed in the process
+    ///
+    /// Fails if another process started listening on one of them meanwhile,
+    /// the core would not be the one answering on it.
+    pub fn release(&mut self) -> anyhow::Result<()> {
+        let addrs = self
+            .held
+            .iter()
+            .map(|socket| socket.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        self.held.clear();
+        for addr in addrs {
+            if std::net::TcpStream::connect_timeout(&addr, RELEASE_CHECK_TIMEOUT).is_ok() {
+                return Err(anyhow::anyhow!(
+                    "Loopback port {} of the broker core taken by another process",
+                    addr.port()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+impl Drop for UpstreamPorts {
+    /// Give the ports back to the process, once bound by the core or unused
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED_UPSTREAM_PORTS.lock().unwrap();
+        for port in &self.ports {
+            claimed.remove(port);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Accept and forward client connections until the shutdown is requested
+///
+/// When this function returns, all the public sockets are closed.
+///
+/// Without any listener, it only waits for the shutdown, the broker keeps
+/// serving its local clients meanwhile.
+pub async fn serve(
+    listeners: Vec<RelayListener>,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut accept_loops = JoinSet::new();
+    for relay in listeners {
+        let listener = TcpListener::from_std(relay.listener)?;
+        accept_loops.spawn(accept_loop(
+            relay.name,
+            listener,
+            relay.upstream,
+            relay.inspection,
+            relay.stats,
+            shutdown.clone(),
+        ));
+    }
+
+    if accept_loops.is_empty() {
+        // A dropped handle also means shutdown
+        let _ = shutdown.wait_for(|stop| *stop).await;
+        return Ok(());
+    }
+    while let Some(result) = accept_loops.join_next().await {
+        result?;
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+/// Accept loop of a single listener
+async fn accept_loop(
+    name: String,
+    listener: TcpListener,
+    upstream: SocketAddr,
+    inspection: Option<Arc<Inspection>>,
+    stats: Option<Arc<BrokerStats>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            // A dropped handle also means shutdown
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    debug!("[{}] new connection from {}", name, peer);
+                    let connection = stats.as_ref().map(|stats| stats.connect(&name, peer));
+                    match &inspection {
+                        Some(inspection) => connections.spawn(inspect::forward(
+                            stream,
+                            upstream,
+                            inspection.clone(),
+                            connection,
+                        )),
+                        None => connections.spawn(forward(stream, upstream, connection)),
+                    };
+                }
+                Err(e) => warn!("[{}] failed to accept connection: {}", name, e),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    // Close the public socket first, then all the client connections
+    drop(listener);
+    connections.shutdown().await;
+    info!("[{}] listener closed", name);
+}
+
+// -----------------------------------------------------------------------------
+
+/// Pipe a client connection to the rumqttd listener
+///
+/// The bytes are added to the statistics as they are forwarded.
+async fn forward(client: TcpStream, upstream: SocketAddr, connection: Option<ConnectionStats>) {
+    let broker = match TcpStream::connect(upstream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Unable to reach broker core on {}: {}", upstream, e);
+            return;
+        }
+    };
+    let _ = client.set_nodelay(true);
+    let _ = broker.set_nodelay(true);
+
+    let (client_rd, client_wr) = client.into_split();
+    let (broker_rd, broker_wr) = broker.into_split();
+    let connection = connection.as_ref();
+    let end_result = tokio::try_join!(
+        pipe(client_rd, broker_wr, |bytes| {
+            if let Some(connection) = connection {
+                connection.received(bytes, false);
+            }
+        }),
+        pipe(broker_rd, client_wr, |bytes| {
+            if let Some(connection) = connection {
+                connection.sent(bytes, false);
+            }
+        }),
+    );
+    if let Err(e) = end_result {
+        debug!("Connection closed: {}", e);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Copy bytes from one half of a connection to the other until the end of the
+/// stream, which is then passed on
+async fn pipe<R, W, F>(mut reader: R, mut writer: W, record: F) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(usize),
+{
+    let mut buffer = vec![0u8; PIPE_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buffer[..read]).await?;
+        record(read);
+    }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    // -------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn piped_bytes_are_counted_before_the_end_of_the_stream() {
+        let (mut client, client_side) = tokio::io::duplex(64);
+        let (broker_side, mut broker) = tokio::io::duplex(64);
+        let counted = Arc::new(AtomicUsize::new(0));
+        let recorded = counted.clone();
+        let piped = tokio::spawn(pipe(client_side, broker_side, move |bytes| {
+            recorded.fetch_add(bytes, Ordering::Relaxed);
+        }));
+
+        client.write_all(b"hello").await.unwrap();
+        let mut received = [0u8; 5];
+        broker.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+        assert_eq!(counted.load(Ordering::Relaxed), 5);
+
+        // The end of the stream is passed on
+        drop(client);
+        piped.await.unwrap().unwrap();
+        assert_eq!(broker.read(&mut received).await.unwrap(), 0);
+    }
+}
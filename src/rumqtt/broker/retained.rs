@@ -0,0 +1,377 @@
+use crate::config::MqttRetainedStoreConfig;
+use crate::rumqtt::util::load_json;
+use crate::rumqtt::util::qos_from_level;
+use crate::rumqtt::util::write_atomic;
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// Maximum total payload size when not configured
+const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+/// Interval between two writes of a modified store
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+// =============================================================================
+
+/// Retained message, as written in the store file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// Topic of the message
+    pub topic: String,
+
+    /// Payload of the message
+    pub payload: Vec<u8>,
+
+    /// Quality of service, as its MQTT level
+    pub qos: u8,
+}
+
+// -----------------------------------------------------------------------------
+
+impl StoredMessage {
+    /// Quality of service, invalid levels fall back to at least once
+    pub fn qos(&self) -> QoS {
+        qos_from_level(self.qos)
+    }
+}
+
+// =============================================================================
+
+/// Retained messages and their total size
+#[derive(Default)]
+struct StoreState {
+    /// Payload and QoS level by topic
+    messages: BTreeMap<String, (Vec<u8>, u8)>,
+
+    /// Total size of the payloads
+    bytes: usize,
+
+    /// True if modified since the last write of the file
+    dirty: bool,
+}
+
+// =============================================================================
+
+/// On-disk copy of the retained messages of the broker
+///
+/// The messages are copied by the hub of the local clients, which sees every
+/// retained publish of the broker. The messages of the local clients keep
+/// their QoS, while the ones of the network clients come through the link of
+/// the hub, subscribed at least once: a QoS 2 message of a network client is
+/// stored, and restored, with QoS 1. The file is replaced at most once per
+/// second, see [`run`], and a corrupt one is set aside on open.
+pub struct RetainedStore {
+    /// File the store is persisted to
+    file: PathBuf,
+
+    /// Held while the file is written or removed
+    writing: Mutex<()>,
+
+    /// Maximum total size of the payloads
+    max_bytes: usize,
+
+    /// Stored messages
+    state: Mutex<StoreState>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl RetainedStore {
+    /// Open the store and load its file, if it exists
+    pub fn open(config: &MqttRetainedStoreConfig) -> anyhow::Result<Self> {
+        let file = config
+            .resolved_file()
+            .ok_or_else(|| anyhow::anyhow!("Unable to determine home directory"))?;
+        let store = Self {
+            file,
+            writing: Mutex::new(()),
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            state: Mutex::new(StoreState::default()),
+        };
+
+        if let Some(messages) = load_json::<Vec<StoredMessage>>(&store.file)? {
+            info!(
+                "{} retained messages loaded from {}",
+                messages.len(),
+                store.file.display()
+            );
+            for message in messages {
+                // The size cap may have changed since the store was saved
+                store.record(&message.topic, &message.payload, message.qos);
+            }
+            store.state.lock().unwrap().dirty = false;
+        }
+        Ok(store)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Store a retained message, an empty payload removes the topic
+    ///
+    /// Messages which would exceed the size cap are not stored.
+    pub fn record(&self, topic: &str, payload: &[u8], qos: u8) {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.messages.get(topic).map(|(p, _)| p.len());
+        if payload.is_empty() {
+            if let Some(previous) = previous {
+                state.messages.remove(topic);
+                state.bytes -= previous;
+                state.dirty = true;
+            }
+            return;
+        }
+
+        if state.messages.get(topic) == Some(&(payload.to_vec(), qos)) {
+            return;
+        }
+        let bytes = state.bytes - previous.unwrap_or(0) + payload.len();
+        if bytes > self.max_bytes {
+            warn!("Retained store full, message on '{}' not stored", topic);
+            return;
+        }
+        state
+            .messages
+            .insert(topic.to_string(), (payload.to_vec(), qos));
+        state.bytes = bytes;
+        state.dirty = true;
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Copy of the stored messages, sorted by topic
+    pub fn messages(&self) -> Vec<StoredMessage> {
+        self.state
+            .lock()
+            .unwrap()
+            .messages
+            .iter()
+            .map(|(topic, (payload, qos))| StoredMessage {
+                topic: topic.clone(),
+                payload: payload.clone(),
+                qos: *qos,
+            })
+            .collect()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Number of stored messages
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// True if no message is stored
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Remove all the messages and the file, returns the removed topics
+    pub fn wipe(&self) -> anyhow::Result<Vec<String>> {
+        let _writing = self.writing.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let topics = state.messages.keys().cloned().collect();
+        *state = StoreState::default();
+        if self.file.exists() {
+            std::fs::remove_file(&self.file)?;
+        }
+        info!("Retained store {} wiped", self.file.display());
+        Ok(topics)
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Write the store into its file if it was modified
+    pub fn persist(&self) {
+        let _writing = self.writing.lock().unwrap();
+        let messages = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return;
+            }
+            state.dirty = false;
+            state
+                .messages
+                .iter()
+                .map(|(topic, (payload, qos))| StoredMessage {
+                    topic: topic.clone(),
+                    payload: payload.clone(),
+                    qos: *qos,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Written without the lock, messages are recorded meanwhile
+        let result = serde_json::to_vec(&messages)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| write_atomic(&self.file, &content));
+        match result {
+            Ok(()) => debug!("{} retained messages persisted", messages.len()),
+            Err(e) => {
+                warn!(
+                    "Unable to persist retained store to {}: {}",
+                    self.file.display(),
+                    e
+                );
+                self.state.lock().unwrap().dirty = true;
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Remove the store file of a configuration, while the broker is not running
+pub fn wipe_retained_store(config: &MqttRetainedStoreConfig) -> anyhow::Result<()> {
+    let file = config
+        .resolved_file()
+        .ok_or_else(|| anyhow::anyhow!("Unable to determine home directory"))?;
+    if file.exists() {
+        std::fs::remove_file(&file)?;
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+/// Write the store periodically while it is modified, never returns
+pub async fn run(store: Option<Arc<RetainedStore>>) {
+    let Some(store) = store else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        store.persist();
+    }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // -------------------------------------------------------------------------
+
+    /// Store configuration of a test, its file removed beforehand
+    fn store_config(name: &str) -> MqttRetainedStoreConfig {
+        let file = std::env::temp_dir().join(format!(
+            "pza-toolkit-retained-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        MqttRetainedStoreConfig {
+            file: Some(file.to_string_lossy().to_string()),
+            ..Default::default()
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Topics and payloads of the stored messages
+    fn contents(store: &RetainedStore) -> Vec<(String, Vec<u8>, u8)> {
+        store
+            .messages()
+            .into_iter()
+            .map(|message| (message.topic, message.payload, message.qos))
+            .collect()
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Path with a suffix added to its name
+    fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+        PathBuf::from(format!("{}{}", file.display(), suffix))
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn store_is_rewritten_and_reloaded_whole() {
+        let config = store_config("rewrite");
+        let store = RetainedStore::open(&config).unwrap();
+        store.record("pza/a", b"1", 2);
+        store.record("pza/b", b"2", 0);
+        store.persist();
+        store.record("pza/a", b"3", 1);
+        store.record("pza/b", b"", 0);
+        store.persist();
+
+        let file = config.resolved_file().unwrap();
+        assert!(!with_suffix(&file, ".tmp").exists());
+        let reloaded = RetainedStore::open(&config).unwrap();
+        assert_eq!(
+            contents(&reloaded),
+            [("pza/a".to_string(), b"3".to_vec(), 1)]
+        );
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn messages_beyond_the_size_cap_are_not_stored() {
+        let config = MqttRetainedStoreConfig {
+            max_bytes: Some(4),
+            ..store_config("cap")
+        };
+        let store = RetainedStore::open(&config).unwrap();
+        store.record("pza/a", b"123", 1);
+        store.record("pza/b", b"45", 1);
+        // Replacing a message only counts the difference
+        store.record("pza/a", b"1234", 1);
+        assert_eq!(
+            contents(&store),
+            [("pza/a".to_string(), b"1234".to_vec(), 1)]
+        );
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn corrupt_file_is_set_aside() {
+        let config = store_config("corrupt");
+        let file = config.resolved_file().unwrap();
+        std::fs::write(&file, b"{ not json").unwrap();
+
+        let store = RetainedStore::open(&config).unwrap();
+        assert!(store.is_empty());
+        assert!(!file.exists());
+        let corrupt = with_suffix(&file, ".corrupt");
+        assert_eq!(std::fs::read(&corrupt).unwrap(), b"{ not json");
+        std::fs::remove_file(&corrupt).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn wipe_removes_the_messages_and_the_file() {
+        let config = store_config("wipe");
+        let store = RetainedStore::open(&config).unwrap();
+        store.record("pza/a", b"1", 1);
+        store.record("pza/b", b"2", 1);
+        store.persist();
+
+        assert_eq!(store.wipe().unwrap(), ["pza/a", "pza/b"]);
+        assert!(store.is_empty());
+        assert!(!config.resolved_file().unwrap().exists());
+        // Nothing left to write
+        store.persist();
+        assert!(!config.resolved_file().unwrap().exists());
+    }
+}
@@ -0,0 +1,458 @@
+use crate::rumqtt::util::now_ms;
+use rumqttd::local::LinkRx;
+use rumqttd::local::LinkTx;
+use rumqttd::meters::MetersLink;
+use rumqttd::Meter;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::debug;
+use tracing::warn;
+
+/// Interval between two computations of the message rates
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Topic prefix of the published statistics when not configured
+pub const DEFAULT_SYS_PREFIX: &str = "$SYS/broker";
+
+// =============================================================================
+
+/// Statistics of a client connection
+///
+/// Client id, username, subscriptions and message counters are only known for
+/// the connections of inspected listeners.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientStats {
+    /// Name of the listener the client is connected to
+    pub listener: String,
+
+    /// Address of the client
+    pub peer: SocketAddr,
+
+    /// Time of the connection, in milliseconds since the Unix epoch
+    pub connected_at_ms: u64,
+
+    /// Client id announced in the CONNECT packet
+    pub client_id: Option<String>,
+
+    /// Username announced in the CONNECT packet
+    pub username: Option<String>,
+
+    /// Filters the client is subscribed to
+    pub subscriptions: Vec<String>,
+
+    /// Messages published by the client
+    pub messages_in: u64,
+
+    /// Messages delivered to the client
+    pub messages_out: u64,
+
+    /// Bytes received from the client
+    pub bytes_in: u64,
+
+    /// Bytes sent to the client
+    pub bytes_out: u64,
+
+    /// Messages published by the client per second
+    pub messages_in_rate: f64,
+
+    /// Messages delivered to the client per second
+    pub messages_out_rate: f64,
+}
+
+// =============================================================================
+
+/// Totals counted by the rumqttd core, for every listener and local client
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CoreTotals {
+    /// Connections of the core, including the relay and the local links
+    pub connections: usize,
+
+    /// Subscriptions of the core
+    pub subscriptions: usize,
+
+    /// Messages published to the core
+    pub publishes: usize,
+
+    /// Messages the core failed to store
+    pub failed_publishes: usize,
+}
+
+// =============================================================================
+
+/// Totals of the broker since its start
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BrokerTotals {
+    /// Clients currently connected
+    pub connections: usize,
+
+    /// Messages published by the clients
+    pub messages_in: u64,
+
+    /// Messages delivered to the clients
+    pub messages_out: u64,
+
+    /// Bytes received from the clients
+    pub bytes_in: u64,
+
+    /// Bytes sent to the clients
+    pub bytes_out: u64,
+
+    /// Messages published by the clients per second
+    pub messages_in_rate: f64,
+
+    /// Messages delivered to the clients per second
+    pub messages_out_rate: f64,
+
+    /// Last totals reported by the core, missing until its first report
+    pub core: Option<CoreTotals>,
+}
+
+// =============================================================================
+
+/// Copy of the broker statistics
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BrokerStatsSnapshot {
+    /// Totals of the broker
+    pub totals: BrokerTotals,
+
+    /// Connected clients, oldest connection first
+    pub clients: Vec<ClientStats>,
+}
+
+// =============================================================================
+
+/// Counters and last rate sample
+#[derive(Default)]
+struct StatsState {
+    /// Connected clients by connection id
+    clients: BTreeMap<u64, ClientStats>,
+
+    /// Totals, the connection count is computed on snapshots
+    totals: BrokerTotals,
+
+    /// Message counters of the clients at the last rate sample
+    samples: HashMap<u64, (u64, u64)>,
+
+    /// Message counters of the totals at the last rate sample
+    totals_sample: (u64, u64),
+
+    /// Time of the last rate sample
+    sampled_at: Option<Instant>,
+}
+
+// =============================================================================
+
+/// Statistics of the broker, collected by its relay and reported by its core
+#[derive(Default)]
+pub struct BrokerStats {
+    /// Counters of the clients and of the broker
+    state: Mutex<StatsState>,
+
+    /// Id of the next connection
+    next_id: AtomicU64,
+}
+
+// -----------------------------------------------------------------------------
+
+impl BrokerStats {
+    /// Register a new client connection, removed when the returned value is
+    /// dropped
+    pub fn connect(self: &Arc<Self>, listener: &str, peer: SocketAddr) -> ConnectionStats {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let client = ClientStats {
+            listener: listener.to_string(),
+            peer,
+            connected_at_ms: now_ms(),
+            client_id: None,
+            username: None,
+            subscriptions: Vec::new(),
+            messages_in: 0,
+            messages_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            messages_in_rate: 0.0,
+            messages_out_rate: 0.0,
+        };
+        self.state.lock().unwrap().clients.insert(id, client);
+        ConnectionStats {
+            id,
+            stats: self.clone(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Copy of the statistics
+    pub fn snapshot(&self) -> BrokerStatsSnapshot {
+        let state = self.state.lock().unwrap();
+        let mut totals = state.totals.clone();
+        totals.connections = state.clients.len();
+        BrokerStatsSnapshot {
+            totals,
+            clients: state.clients.values().cloned().collect(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record the totals reported by the core on its meters link
+    ///
+    /// The link is read on its own thread, as it blocks. The thread ends with
+    /// the link, or at the first report after the statistics are dropped.
+    pub fn watch_core(self: &Arc<Self>, meters: MetersLink) -> std::io::Result<()> {
+        let stats = Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("broker-meters".into())
+            .spawn(move || read_core_meters(meters, stats))?;
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Update the message rates from the counters
+    fn sample(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let elapsed = state
+            .sampled_at
+            .map(|sampled_at| now.duration_since(sampled_at).as_secs_f64())
+            .unwrap_or_default();
+
+        let rate = |count: u64, previous: u64| match elapsed > 0.0 {
+            true => count.saturating_sub(previous) as f64 / elapsed,
+            false => 0.0,
+        };
+
+        let mut samples = HashMap::with_capacity(state.clients.len());
+        for (id, client) in state.clients.iter_mut() {
+            let (previous_in, previous_out) = state
+                .samples
+                .get(id)
+                .copied()
+                .unwrap_or((client.messages_in, client.messages_out));
+            client.messages_in_rate = rate(client.messages_in, previous_in);
+            client.messages_out_rate = rate(client.messages_out, previous_out);
+            samples.insert(*id, (client.messages_in, client.messages_out));
+        }
+
+        let totals = &mut state.totals;
+        totals.messages_in_rate = rate(totals.messages_in, state.totals_sample.0);
+        totals.messages_out_rate = rate(totals.messages_out, state.totals_sample.1);
+        state.totals_sample = (totals.messages_in, totals.messages_out);
+        state.samples = samples;
+        state.sampled_at = Some(now);
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Apply a change to a connected client
+    fn update<F: FnOnce(&mut ClientStats, &mut BrokerTotals)>(&self, id: u64, change: F) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(client) = state.clients.get_mut(&id) {
+            change(client, &mut state.totals);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Copy the router meters of the core into the statistics, until the link or
+/// the statistics are gone
+fn read_core_meters(meters: MetersLink, stats: Weak<BrokerStats>) {
+    while let Ok(reports) = meters.recv() {
+        let Some(stats) = stats.upgrade() else {
+            return;
+        };
+        for report in reports {
+            if let Meter::Router(_, meter) = report {
+                stats.state.lock().unwrap().totals.core = Some(CoreTotals {
+                    connections: meter.total_connections,
+                    subscriptions: meter.total_subscriptions,
+                    publishes: meter.total_publishes,
+                    failed_publishes: meter.failed_publishes,
+                });
+            }
+        }
+    }
+    debug!("Broker core meters link closed");
+}
+
+// =============================================================================
+
+/// Statistics of a single connection, held by the relay while it forwards it
+pub struct ConnectionStats {
+    /// Id of the connection
+    id: u64,
+
+    /// Statistics of the broker
+    stats: Arc<BrokerStats>,
+}
+
+// -----------------------------------------------------------------------------
+
+impl ConnectionStats {
+    /// Record the identity announced by the client
+    pub fn identify(&self, client_id: &str, username: Option<&str>) {
+        self.stats.update(self.id, |client, _| {
+            client.client_id = Some(client_id.to_string());
+            client.username = username.map(str::to_string);
+        });
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record a packet received from the client
+    pub fn received(&self, bytes: usize, publish: bool) {
+        self.stats.update(self.id, |client, totals| {
+            client.bytes_in += bytes as u64;
+            totals.bytes_in += bytes as u64;
+            if publish {
+                client.messages_in += 1;
+                totals.messages_in += 1;
+            }
+        });
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record a packet sent to the client
+    pub fn sent(&self, bytes: usize, publish: bool) {
+        self.stats.update(self.id, |client, totals| {
+            client.bytes_out += bytes as u64;
+            totals.bytes_out += bytes as u64;
+            if publish {
+                client.messages_out += 1;
+                totals.messages_out += 1;
+            }
+        });
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record the filters granted by the broker
+    pub fn subscribed(&self, filters: Vec<String>) {
+        self.stats.update(self.id, |client, _| {
+            for filter in filters {
+                if !client.subscriptions.contains(&filter) {
+                    client.subscriptions.push(filter);
+                }
+            }
+        });
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// Record the filters removed by the client
+    pub fn unsubscribed(&self, filters: &[String]) {
+        self.stats.update(self.id, |client, _| {
+            client
+                .subscriptions
+                .retain(|subscription| !filters.contains(subscription));
+        });
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+impl Drop for ConnectionStats {
+    /// Remove the client once its connection is closed
+    fn drop(&mut self) {
+        let mut state = self.stats.state.lock().unwrap();
+        state.clients.remove(&self.id);
+        state.samples.remove(&self.id);
+    }
+}
+
+// =============================================================================
+
+/// Local link of the broker core used to publish the statistics
+pub struct SysPublisher {
+    /// Sending half of the link
+    pub tx: LinkTx,
+
+    /// Receiving half of the link, only drained
+    pub rx: LinkRx,
+
+    /// Topic prefix of the statistics
+    pub prefix: String,
+
+    /// Interval between two publications
+    pub interval: Duration,
+}
+
+// -----------------------------------------------------------------------------
+
+impl SysPublisher {
+    /// Publish the statistics as JSON
+    ///
+    /// `{prefix}/stats` holds the totals and `{prefix}/clients` the connected
+    /// clients.
+    fn publish(&mut self, snapshot: &BrokerStatsSnapshot) -> anyhow::Result<()> {
+        let totals = serde_json::to_vec(&snapshot.totals)?;
+        let clients = serde_json::to_vec(&snapshot.clients)?;
+        self.tx
+            .publish(format!("{}/stats", self.prefix), totals)
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        self.tx
+            .publish(format!("{}/clients", self.prefix), clients)
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Compute the message rates and publish the statistics, never returns
+pub async fn run(stats: Option<Arc<BrokerStats>>, mut sys: Option<SysPublisher>) {
+    let Some(stats) = stats else {
+        return std::future::pending().await;
+    };
+
+    let mut rates = tokio::time::interval(RATE_INTERVAL);
+    let sys_interval = sys
+        .as_ref()
+        .map(|sys| sys.interval)
+        .unwrap_or(RATE_INTERVAL);
+    let mut publications = tokio::time::interval(sys_interval.max(RATE_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = rates.tick() => stats.sample(),
+            _ = publications.tick(), if sys.is_some() => {
+                if let Some(publisher) = &mut sys {
+                    if let Err(e) = publisher.publish(&stats.snapshot()) {
+                        warn!("Broker statistics no longer published: {}", e);
+                        sys = None;
+                    }
+                }
+            }
+            open = next_notification(&mut sys) => {
+                if !open {
+                    warn!("Broker statistics link closed");
+                    sys = None;
+                }
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Drain a notification sent by the core to the statistics link, returns false
+/// once the link is closed
+async fn next_notification(sys: &mut Option<SysPublisher>) -> bool {
+    match sys {
+        Some(sys) => sys.rx.next().await.is_ok(),
+        None => std::future::pending().await,
+    }
+}
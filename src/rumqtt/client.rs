@@ -1,27 +1,376 @@
+use crate::config::IPEndpointConfig;
+use crate::config::MqttBrokerConfig;
+use crate::config::MqttClientConfig;
+use crate::config::MqttCredentialsConfig;
+use crate::config::MqttOfflineQueueConfig;
 use crate::rand::generate_random_string;
+use crate::rumqtt::dispatch::TopicDispatcher;
+use crate::rumqtt::driver::ConnectionState;
+use crate::rumqtt::driver::MqttDriver;
+use crate::rumqtt::driver::ReconnectPolicy;
+use crate::rumqtt::json::JsonError;
+use crate::rumqtt::offline_queue::OfflinePublisher;
+use crate::rumqtt::publish::PublishOptions;
+use crate::rumqtt::publish::PublishPolicy;
+use crate::rumqtt::rpc::RpcRequester;
+use crate::rumqtt::state_cache::StateCache;
 use rumqttc::AsyncClient;
+use rumqttc::LastWill;
 use rumqttc::MqttOptions;
 use std::time::Duration;
+use tracing::warn;
+
+/// Broker host used when no endpoint is configured
+const DEFAULT_HOST: &str = "localhost";
+
+/// Broker port used when no endpoint is configured
+const DEFAULT_PORT: u16 = 1883;
+
+/// Keep-alive interval used when not configured
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(3);
+
+/// Request channel capacity used when not configured
+const DEFAULT_CAPACITY: usize = 100;
+
+/// Payload of the status topic while the client is connected
+pub const STATUS_ONLINE: &str = "online";
+
+/// Payload of the status topic once the client is gone
+pub const STATUS_OFFLINE: &str = "offline";
 
 // -------------------------------------------------------------------------------
 
 /// MQTT initialization utilities
 pub fn init_client<A: Into<String>>(module_name: A) -> (AsyncClient, rumqttc::EventLoop) {
-    // Generate a unique client ID
-    let client_id = format!("{}-{}", module_name.into(), generate_random_string(5));
+    MqttClientBuilder::new(module_name).build()
+}
+
+// -------------------------------------------------------------------------------
+
+/// MQTT initialization utilities for brokers requiring authentication
+pub fn init_client_with_credentials<A: Into<String>>(
+    module_name: A,
+    credentials: Option<&MqttCredentialsConfig>,
+) -> (AsyncClient, rumqttc::EventLoop) {
+    let mut builder = MqttClientBuilder::new(module_name);
+    if let Some(credentials) = credentials {
+        builder = builder.credentials(credentials);
+    }
+    builder.build()
+}
+
+// ===============================================================================
+
+/// Reason why a topic could not be subscribed
+#[derive(Debug, thiserror::Error)]
+pub enum SubscribeFailure {
+    /// The request could not be given to the event loop, which is stopped or
+    /// overloaded
+    #[error("request not sent to the event loop")]
+    NotSent,
+
+    /// The broker refused the subscription
+    #[error("denied by the broker")]
+    Denied,
+
+    /// The connection was lost before the SUBACK
+    #[error("no SUBACK received")]
+    NoAck,
+}
+
+// -------------------------------------------------------------------------------
+
+/// Error of a subscription to many topics, listing the failed ones
+#[derive(Debug, thiserror::Error)]
+#[error("{} subscription(s) failed: {}", .failures.len(), failed_topics(.failures))]
+pub struct SubscribeError {
+    /// Failed topics with the reason of the failure
+    pub failures: Vec<(String, SubscribeFailure)>,
+}
+
+// -------------------------------------------------------------------------------
+
+/// Failed topics and reasons, for the error message
+fn failed_topics(failures: &[(String, SubscribeFailure)]) -> String {
+    failures
+        .iter()
+        .map(|(topic, reason)| format!("'{}' ({})", topic, reason))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// ===============================================================================
+
+/// Builder of MQTT clients
+///
+/// Without any option, the client connects to `localhost:1883` like
+/// [`init_client`]. With [`MqttClientBuilder::from_broker_config`], the same
+/// configuration drives the broker and all the clients.
+///
+/// ```ignore
+/// let (client, event_loop) = MqttClientBuilder::from_broker_config("my_module", &broker_config)
+///     .liveness("pza/my_module")
+///     .build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct MqttClientBuilder {
+    /// Module name, the client ID is derived from it
+    module_name: String,
+    /// Broker host
+    host: String,
+    /// Broker port
+    port: u16,
+    /// Keep-alive interval
+    keep_alive: Duration,
+    /// Request channel capacity
+    capacity: usize,
+    /// Clean session flag
+    clean_session: bool,
+    /// Message published by the broker when the client disconnects abnormally
+    last_will: Option<LastWill>,
+    /// User name and password
+    credentials: Option<(String, String)>,
+    /// Liveness topic, see [`MqttClientBuilder::liveness`]
+    status_topic: Option<String>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl MqttClientBuilder {
+    /// Create a builder with the default options
+    pub fn new<A: Into<String>>(module_name: A) -> Self {
+        Self {
+            module_name: module_name.into(),
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            capacity: DEFAULT_CAPACITY,
+            clean_session: true,
+            last_will: None,
+            credentials: None,
+            status_topic: None,
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create a builder connecting to the TCP endpoint of a broker configuration
+    ///
+    /// The credentials and client options of the configuration are applied too.
+    pub fn from_broker_config<A: Into<String>>(
+        module_name: A,
+        broker_config: &MqttBrokerConfig,
+    ) -> Self {
+        let mut builder = Self::new(module_name);
+        if let Some(tcp) = &broker_config.tcp {
+            builder = builder.endpoint(tcp);
+        }
+        if let Some(credentials) = &broker_config.credentials {
+            builder = builder.credentials(credentials);
+        }
+        if let Some(client_config) = &broker_config.client {
+            builder = builder.client_config(client_config);
+        }
+        builder
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Connect to this endpoint
+    ///
+    /// Wildcard addresses like `0.0.0.0`, used by broker listeners, are reached
+    /// through `localhost`.
+    pub fn endpoint(mut self, endpoint: &IPEndpointConfig) -> Self {
+        if let Some(addr) = &endpoint.addr {
+            self.host = match addr.parse::<std::net::IpAddr>() {
+                Ok(ip) if ip.is_unspecified() => DEFAULT_HOST.to_string(),
+                _ => addr.clone(),
+            };
+        }
+        if let Some(port) = endpoint.port {
+            self.port = port;
+        }
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Apply the client options of a configuration, missing ones are unchanged
+    pub fn client_config(mut self, client_config: &MqttClientConfig) -> Self {
+        if let Some(keep_alive_secs) = client_config.keep_alive_secs {
+            self.keep_alive = Duration::from_secs(keep_alive_secs);
+        }
+        if let Some(capacity) = client_config.capacity {
+            self.capacity = capacity;
+        }
+        if let Some(clean_session) = client_config.clean_session {
+            self.clean_session = clean_session;
+        }
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the keep-alive interval
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the capacity of the request channel between the client and its event loop
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the clean session flag
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the message published by the broker when the client disconnects abnormally
+    pub fn last_will(mut self, last_will: LastWill) -> Self {
+        self.last_will = Some(last_will);
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Expose the liveness of the client on `{prefix}/status`
+    ///
+    /// A retained "offline" last will is registered. With
+    /// [`MqttClientBuilder::spawn_driver`], a retained "online" birth message is
+    /// published on each connection and "offline" on `MqttDriver::stop`.
+    pub fn liveness<A: AsRef<str>>(mut self, prefix: A) -> Self {
+        let status_topic = status_topic(prefix.as_ref());
+        self.last_will = Some(LastWill::new(
+            status_topic.clone(),
+            STATUS_OFFLINE,
+            rumqttc::QoS::AtLeastOnce,
+            true,
+        ));
+        self.status_topic = Some(status_topic);
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the credentials, ignored if no user name is configured
+    pub fn credentials(mut self, credentials: &MqttCredentialsConfig) -> Self {
+        if let Some(username) = &credentials.username {
+            self.credentials = Some((
+                username.clone(),
+                credentials.password.clone().unwrap_or_default(),
+            ));
+        }
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// MQTT options of the client, with a new unique client ID
+    pub fn mqtt_options(&self) -> MqttOptions {
+        // Generate a unique client ID
+        let client_id = format!("{}-{}", self.module_name, generate_random_string(5));
 
-    // Initialize MQTT client
-    let mut mqttoptions = MqttOptions::new(client_id, "localhost", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(3));
+        let mut mqttoptions = MqttOptions::new(client_id, self.host.clone(), self.port);
+        mqttoptions.set_keep_alive(self.keep_alive);
+        mqttoptions.set_clean_session(self.clean_session);
+        if let Some(last_will) = &self.last_will {
+            mqttoptions.set_last_will(last_will.clone());
+        }
+        if let Some((username, password)) = &self.credentials {
+            mqttoptions.set_credentials(username, password);
+        }
+        mqttoptions
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create the AsyncClient and its EventLoop
+    pub fn build(self) -> (AsyncClient, rumqttc::EventLoop) {
+        AsyncClient::new(self.mqtt_options(), self.capacity)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// MQTT v5 options of the client, with a new unique client ID
+    pub fn mqtt_options_v5(&self) -> rumqttc::v5::MqttOptions {
+        // Generate a unique client ID
+        let client_id = format!("{}-{}", self.module_name, generate_random_string(5));
+
+        let mut mqttoptions =
+            rumqttc::v5::MqttOptions::new(client_id, self.host.clone(), self.port);
+        mqttoptions.set_keep_alive(self.keep_alive);
+        mqttoptions.set_clean_start(self.clean_session);
+        if let Some(last_will) = &self.last_will {
+            mqttoptions.set_last_will(rumqttc::v5::mqttbytes::v5::LastWill {
+                topic: last_will.topic.clone().into(),
+                message: last_will.message.clone(),
+                qos: qos_to_v5(last_will.qos),
+                retain: last_will.retain,
+                properties: None,
+            });
+        }
+        if let Some((username, password)) = &self.credentials {
+            mqttoptions.set_credentials(username, password);
+        }
+        mqttoptions
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create the MQTT v5 AsyncClient and its EventLoop
+    pub fn build_v5(self) -> (rumqttc::v5::AsyncClient, rumqttc::v5::EventLoop) {
+        rumqttc::v5::AsyncClient::new(self.mqtt_options_v5(), self.capacity)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create the client and spawn a driver running its event loop
+    ///
+    /// Must be called from a tokio runtime.
+    pub fn spawn_driver(
+        self,
+        policy: ReconnectPolicy,
+    ) -> (MqttDriver, tokio::sync::mpsc::Receiver<rumqttc::Publish>) {
+        let status_topic = self.status_topic.clone();
+        let (client, event_loop) = self.build();
+        MqttDriver::spawn_with_status(client, event_loop, policy, status_topic)
+    }
+}
+
+// -------------------------------------------------------------------------------
 
-    // Create the AsyncClient and EventLoop
-    let (client, event_loop) = AsyncClient::new(mqttoptions, 100);
-    (client, event_loop)
+/// Liveness topic of a prefix, like `topic_with_prefix("status")`
+pub fn status_topic(prefix: &str) -> String {
+    format!("{}/status", prefix)
+}
+
+// -------------------------------------------------------------------------------
+
+/// Convert a v3.1.1 QoS into its v5 equivalent
+pub fn qos_to_v5(qos: rumqttc::QoS) -> rumqttc::v5::mqttbytes::QoS {
+    match qos {
+        rumqttc::QoS::AtMostOnce => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+        rumqttc::QoS::AtLeastOnce => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+        rumqttc::QoS::ExactlyOnce => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+    }
 }
 
 // ===============================================================================
 
 /// Custom wrapper around rumqttc::AsyncClient with predefined QoS and retain settings
+///
+/// To connect to a broker requiring authentication or to an external broker,
+/// wrap a client created with [`MqttClientBuilder`].
 #[derive(Clone)]
 pub struct RumqttCustomAsyncClient {
     /// The underlying MQTT asynchronous client
@@ -32,6 +381,9 @@ pub struct RumqttCustomAsyncClient {
     pub retain: bool,
 
     pub prefix: String,
+
+    /// QoS and retain settings by topic, overriding the predefined ones
+    pub policy: PublishPolicy,
 }
 
 // -------------------------------------------------------------------------------
@@ -44,37 +396,179 @@ impl RumqttCustomAsyncClient {
             qos,
             retain,
             prefix,
+            policy: PublishPolicy::default(),
         }
     }
 
     // ---------------------------------------------------------------------------
 
+    /// Use a policy to choose the QoS and retain settings of each topic
+    pub fn with_policy(mut self, policy: PublishPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
     /// Subscribe to all relevant MQTT topics
-    pub async fn subscribe_to_all(&self, topics: Vec<String>) {
+    ///
+    /// Every topic is tried, the ones whose request could not be sent are
+    /// listed in the error. Use `MqttDriver::subscribe_many` to also check the
+    /// answer of the broker.
+    pub async fn subscribe_to_all(&self, topics: Vec<String>) -> Result<(), SubscribeError> {
+        let mut failures = Vec::new();
         for topic in topics {
-            self.client.subscribe(topic, self.qos).await.unwrap();
+            if let Err(e) = self.client.subscribe(topic.clone(), self.qos).await {
+                warn!("Unable to subscribe to '{}': {}", topic, e);
+                failures.push((topic, SubscribeFailure::NotSent));
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(SubscribeError { failures }),
         }
     }
 
     // ---------------------------------------------------------------------------
 
-    /// Publish a message to a topic using the predefined QoS and retain settings
+    /// Publish a message to a topic using the predefined QoS and retain settings,
+    /// or the ones of the policy for this topic
     pub async fn publish<A: Into<String>, V: Into<Vec<u8>>>(
         &self,
         topic: A,
         payload: V,
     ) -> Result<(), rumqttc::ClientError> {
-        self.client
-            .publish(topic.into(), self.qos, self.retain, payload)
+        self.publish_with(topic, payload, PublishOptions::default())
             .await
     }
 
     // ---------------------------------------------------------------------------
 
+    /// Publish a message with options overriding the policy and predefined settings
+    pub async fn publish_with<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+        options: PublishOptions,
+    ) -> Result<(), rumqttc::ClientError> {
+        let topic = topic.into();
+        let (qos, retain) = self.resolve_options(&topic, options);
+        self.client.publish(topic, qos, retain, payload).await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// QoS and retain flag of a publish, from the options, then the policy and
+    /// then the predefined settings
+    pub fn resolve_options(&self, topic: &str, options: PublishOptions) -> (rumqttc::QoS, bool) {
+        let options = options.or(self.policy.options_for(&self.prefix, topic));
+        (
+            options.qos.unwrap_or(self.qos),
+            options.retain.unwrap_or(self.retain),
+        )
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish a value serialized as JSON, using the predefined QoS and retain settings
+    pub async fn publish_json<A: Into<String>, T: serde::Serialize>(
+        &self,
+        topic: A,
+        value: &T,
+    ) -> Result<(), JsonError> {
+        let payload = serde_json::to_vec(value).map_err(JsonError::Serialize)?;
+        self.publish(topic, payload).await?;
+        Ok(())
+    }
+
+    // ---------------------------------------------------------------------------
+
     /// Generate a topic string with the configured prefix
     pub fn topic_with_prefix<A: AsRef<str>>(&self, topic: A) -> String {
         format!("{}/{}", self.prefix, topic.as_ref())
     }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish the retained liveness status of the client on `{prefix}/status`
+    ///
+    /// Useful without a driver, which publishes it by itself.
+    pub async fn publish_status(&self, online: bool) -> Result<(), rumqttc::ClientError> {
+        let payload = match online {
+            true => STATUS_ONLINE,
+            false => STATUS_OFFLINE,
+        };
+        self.client
+            .publish(
+                status_topic(&self.prefix),
+                rumqttc::QoS::AtLeastOnce,
+                true,
+                payload,
+            )
+            .await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create a dispatcher whose filters are relative to the configured prefix
+    pub fn dispatcher(&self) -> TopicDispatcher {
+        TopicDispatcher::new(self.prefix.clone())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Subscribe to topic filters relative to the configured prefix and create
+    /// a cache of their last values
+    ///
+    /// The cache is opt-in: it is only fed once the incoming publishes go
+    /// through [`StateCache::intercept`]. It is returned even if some
+    /// subscriptions failed, as they can be retried with
+    /// `subscribe_to_all(cache.filters().to_vec())`.
+    pub async fn subscribe_cached<A: AsRef<str>>(
+        &self,
+        topics: Vec<A>,
+    ) -> (StateCache, Result<(), SubscribeError>) {
+        let filters: Vec<String> = topics
+            .iter()
+            .map(|topic| self.topic_with_prefix(topic))
+            .collect();
+        let cache = StateCache::new(filters.clone());
+        let result = self.subscribe_to_all(filters).await;
+        (cache, result)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create a publisher queueing the messages while the client is disconnected
+    ///
+    /// `state` is the connection state of the driver of the client.
+    pub fn offline_publisher(
+        &self,
+        state: tokio::sync::watch::Receiver<ConnectionState>,
+        config: &MqttOfflineQueueConfig,
+    ) -> anyhow::Result<OfflinePublisher> {
+        OfflinePublisher::new(self.clone(), state, config)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Create a requester sending RPC requests with this client
+    pub fn requester(&self) -> RpcRequester {
+        RpcRequester::new(self.clone())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Subscribe to the filters of all the routes of a dispatcher
+    ///
+    /// Every filter is tried, the ones whose request could not be sent are
+    /// listed in the error, like with [`Self::subscribe_to_all`].
+    pub async fn subscribe_routes(
+        &self,
+        dispatcher: &TopicDispatcher,
+    ) -> Result<(), SubscribeError> {
+        self.subscribe_to_all(dispatcher.filters()).await
+    }
 }
 
 // ===============================================================================
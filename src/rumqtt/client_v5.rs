@@ -0,0 +1,261 @@
+use crate::config::MqttBrokerConfig;
+use crate::rumqtt::client::MqttClientBuilder;
+use crate::rumqtt::client::RumqttCustomAsyncClient;
+use crate::rumqtt::client::SubscribeError;
+use crate::rumqtt::client::SubscribeFailure;
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::v5::mqttbytes::QoS;
+use std::time::Duration;
+use tracing::debug;
+use tracing::warn;
+
+// ===============================================================================
+
+/// Create a v5 client connecting to the MQTT v5 endpoint of a broker
+/// configuration (`tcp_v5`)
+pub fn init_client_v5<A: Into<String>>(
+    module_name: A,
+    broker_config: &MqttBrokerConfig,
+) -> (rumqttc::v5::AsyncClient, rumqttc::v5::EventLoop) {
+    let mut builder = MqttClientBuilder::from_broker_config(module_name, broker_config);
+    if let Some(tcp_v5) = &broker_config.tcp_v5 {
+        builder = builder.endpoint(tcp_v5);
+    }
+    builder.build_v5()
+}
+
+// ===============================================================================
+
+/// MQTT v5 properties of a published message
+///
+/// ```ignore
+/// let properties = MessageProperties::new()
+///     .message_expiry(Duration::from_secs(5))
+///     .user_property("unit", "V");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MessageProperties {
+    /// Properties as sent in the PUBLISH packet
+    properties: PublishProperties,
+}
+
+// -------------------------------------------------------------------------------
+
+impl MessageProperties {
+    /// Create empty properties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Add a user property, like a unit or a timestamp
+    pub fn user_property<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.properties
+            .user_properties
+            .push((key.into(), value.into()));
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Drop the message if it is not delivered in time, with a second
+    /// resolution
+    pub fn message_expiry(mut self, expiry: Duration) -> Self {
+        let seconds = expiry.as_secs().min(u32::MAX as u64) as u32;
+        self.properties.message_expiry_interval = Some(seconds);
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Topic the receiver should answer on
+    pub fn response_topic<A: Into<String>>(mut self, topic: A) -> Self {
+        self.properties.response_topic = Some(topic.into());
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Data identifying the request of a response
+    pub fn correlation_data<V: Into<Bytes>>(mut self, data: V) -> Self {
+        self.properties.correlation_data = Some(data.into());
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Use a topic alias, the broker limits their number per connection
+    pub fn topic_alias(mut self, alias: u16) -> Self {
+        self.properties.topic_alias = Some(alias);
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// MIME type of the payload
+    pub fn content_type<A: Into<String>>(mut self, content_type: A) -> Self {
+        self.properties.content_type = Some(content_type.into());
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Properties as sent in the PUBLISH packet
+    pub fn into_publish_properties(self) -> PublishProperties {
+        self.properties
+    }
+}
+
+// ===============================================================================
+
+/// MQTT v5 flavour of [`RumqttCustomAsyncClient`]
+#[derive(Clone)]
+pub struct RumqttV5CustomAsyncClient {
+    /// The underlying MQTT v5 asynchronous client
+    pub client: rumqttc::v5::AsyncClient,
+    /// Quality of Service level for MQTT messages
+    pub qos: QoS,
+    /// Retain flag for MQTT messages
+    pub retain: bool,
+
+    pub prefix: String,
+}
+
+// -------------------------------------------------------------------------------
+
+impl RumqttV5CustomAsyncClient {
+    /// Create a new RumqttV5CustomAsyncClient with specified QoS and retain settings
+    pub fn new(client: rumqttc::v5::AsyncClient, qos: QoS, retain: bool, prefix: String) -> Self {
+        Self {
+            client,
+            qos,
+            retain,
+            prefix,
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Subscribe to all relevant MQTT topics
+    ///
+    /// Every topic is tried, the ones whose request could not be sent are
+    /// listed in the error.
+    pub async fn subscribe_to_all(&self, topics: Vec<String>) -> Result<(), SubscribeError> {
+        let mut failures = Vec::new();
+        for topic in topics {
+            if let Err(e) = self.client.subscribe(topic.clone(), self.qos).await {
+                warn!("Unable to subscribe to '{}': {}", topic, e);
+                failures.push((topic, SubscribeFailure::NotSent));
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(SubscribeError { failures }),
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish a message to a topic using the predefined QoS and retain settings
+    pub async fn publish<A: Into<String>, V: Into<Bytes>>(
+        &self,
+        topic: A,
+        payload: V,
+    ) -> Result<(), rumqttc::v5::ClientError> {
+        self.client
+            .publish(topic.into(), self.qos, self.retain, payload)
+            .await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish a message with v5 properties
+    pub async fn publish_with_properties<A: Into<String>, V: Into<Bytes>>(
+        &self,
+        topic: A,
+        payload: V,
+        properties: MessageProperties,
+    ) -> Result<(), rumqttc::v5::ClientError> {
+        self.client
+            .publish_with_properties(
+                topic.into(),
+                self.qos,
+                self.retain,
+                payload,
+                properties.into_publish_properties(),
+            )
+            .await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Generate a topic string with the configured prefix
+    pub fn topic_with_prefix<A: AsRef<str>>(&self, topic: A) -> String {
+        format!("{}/{}", self.prefix, topic.as_ref())
+    }
+}
+
+// ===============================================================================
+
+/// Custom client of either protocol version
+///
+/// Lets the same code publish through v3.1.1 and v5 clients. The v5 properties
+/// are ignored by v3.1.1 clients.
+#[derive(Clone)]
+pub enum RumqttAnyCustomAsyncClient {
+    /// MQTT v3.1.1 client
+    V4(RumqttCustomAsyncClient),
+    /// MQTT v5 client
+    V5(RumqttV5CustomAsyncClient),
+}
+
+// -------------------------------------------------------------------------------
+
+impl RumqttAnyCustomAsyncClient {
+    /// Publish a message with v5 properties, dropped on v3.1.1
+    pub async fn publish_with_properties<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+        properties: MessageProperties,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::V4(client) => {
+                if properties.properties != PublishProperties::default() {
+                    debug!("MQTT v5 properties ignored by v3.1.1 client");
+                }
+                client.publish(topic, payload).await?;
+            }
+            Self::V5(client) => {
+                client
+                    .publish_with_properties(topic, payload.into(), properties)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish a message to a topic using the predefined QoS and retain settings
+    pub async fn publish<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+    ) -> anyhow::Result<()> {
+        self.publish_with_properties(topic, payload, MessageProperties::new())
+            .await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Generate a topic string with the configured prefix
+    pub fn topic_with_prefix<A: AsRef<str>>(&self, topic: A) -> String {
+        match self {
+            Self::V4(client) => client.topic_with_prefix(topic),
+            Self::V5(client) => client.topic_with_prefix(topic),
+        }
+    }
+}
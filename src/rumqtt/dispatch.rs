@@ -0,0 +1,291 @@
+use crate::rumqtt::json::decode_json;
+use crate::rumqtt::json::JsonSubscription;
+use crate::rumqtt::topic::filter_captures;
+use bytes::Bytes;
+use rumqttc::Event;
+use rumqttc::Packet;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::debug;
+use tracing::warn;
+
+/// Capacity of the channel of a JSON route
+const JSON_ROUTE_CAPACITY: usize = 100;
+
+// ===============================================================================
+
+/// Incoming message routed to a handler
+#[derive(Clone, Debug)]
+pub struct TopicMessage {
+    /// Full topic of the message
+    pub topic: String,
+
+    /// Topic levels matched by the wildcards of the handler filter, in order
+    ///
+    /// With the filter `+/cmd/#`, the topic `prefix/psu/cmd/voltage/set` gives
+    /// `["psu", "voltage/set"]`.
+    pub captures: Vec<String>,
+
+    /// Payload of the message
+    pub payload: Bytes,
+
+    /// Quality of service of the message
+    pub qos: QoS,
+
+    /// True if the message was retained by the broker
+    pub retain: bool,
+}
+
+// ===============================================================================
+
+/// Boxed future returned by the handlers
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Handler registered on a topic filter
+type Handler = Arc<dyn Fn(TopicMessage) -> HandlerFuture + Send + Sync>;
+
+// ===============================================================================
+
+/// Handler and the prefixed filter it is registered on
+struct Route {
+    /// Topic filter, with the prefix
+    filter: String,
+
+    /// Handler called for each matching message
+    handler: Handler,
+}
+
+// ===============================================================================
+
+/// Routes incoming publishes to async handlers by topic filter
+///
+/// Filters are relative to the prefix of the client, like the topics given to
+/// `topic_with_prefix`, and may use `+` and `#` wildcards. A message is given
+/// to every handler whose filter matches, in registration order.
+///
+/// ```ignore
+/// let mut dispatcher = client.dispatcher();
+/// dispatcher.route("+/cmd", |message| async move {
+///     println!("command for {}", message.captures[0]);
+/// });
+/// client.subscribe_routes(&dispatcher).await?;
+/// dispatcher.run(incoming).await;
+/// ```
+pub struct TopicDispatcher {
+    /// Prefix prepended to the route filters
+    prefix: String,
+
+    /// Registered routes
+    routes: Vec<Route>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl TopicDispatcher {
+    /// Create a dispatcher whose filters are relative to `prefix`
+    pub fn new<A: Into<String>>(prefix: A) -> Self {
+        Self {
+            prefix: prefix.into(),
+            routes: Vec::new(),
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Prefix prepended to the route filters
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Register an async handler on a topic filter relative to the prefix
+    pub fn route<A, F, Fut>(&mut self, filter: A, handler: F) -> &mut Self
+    where
+        A: AsRef<str>,
+        F: Fn(TopicMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let filter = format!("{}/{}", self.prefix, filter.as_ref());
+        self.routes.push(Route {
+            filter,
+            handler: Arc::new(move |message| Box::pin(handler(message))),
+        });
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Register a route parsing the payloads as JSON values of type `T`
+    ///
+    /// Up to 100 messages wait for the subscription, the following ones are
+    /// dropped with a warning until it catches up.
+    pub fn route_json<T, A>(&mut self, filter: A) -> JsonSubscription<T>
+    where
+        T: DeserializeOwned + Send + 'static,
+        A: AsRef<str>,
+    {
+        let (sender, receiver) = mpsc::channel(JSON_ROUTE_CAPACITY);
+        self.route(filter, move |message| {
+            // Waiting for the receiver would hold the other routes, so a slow
+            // receiver loses messages. Nobody listening is not an error.
+            let topic = message.topic.clone();
+            if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(decode_json(message)) {
+                warn!(
+                    "JSON message on '{}' dropped, its receiver is too slow",
+                    topic
+                );
+            }
+            std::future::ready(())
+        });
+        JsonSubscription { receiver }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Prefixed filters of the routes, to subscribe to
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = Vec::new();
+        for route in &self.routes {
+            if !filters.contains(&route.filter) {
+                filters.push(route.filter.clone());
+            }
+        }
+        filters
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Give a publish to all the matching handlers, returns how many were called
+    pub async fn dispatch(&self, publish: &Publish) -> usize {
+        let mut called = 0;
+        for route in &self.routes {
+            if let Some(captures) = filter_captures(&route.filter, &publish.topic) {
+                let message = TopicMessage {
+                    topic: publish.topic.clone(),
+                    captures,
+                    payload: publish.payload.clone(),
+                    qos: publish.qos,
+                    retain: publish.retain,
+                };
+                (route.handler)(message).await;
+                called += 1;
+            }
+        }
+        if called == 0 {
+            debug!("No handler for topic '{}'", publish.topic);
+        }
+        called
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Dispatch the incoming publishes of an event loop event
+    pub async fn dispatch_event(&self, event: &Event) {
+        if let Event::Incoming(Packet::Publish(publish)) = event {
+            self.dispatch(publish).await;
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Dispatch the publishes of a channel, like the one of an `MqttDriver`,
+    /// until it is closed
+    pub async fn run(self, mut incoming: mpsc::Receiver<Publish>) {
+        while let Some(publish) = incoming.recv().await {
+            self.dispatch(&publish).await;
+        }
+    }
+}
+
+// ===============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rumqtt::json::JsonError;
+    use std::sync::Mutex;
+
+    // ---------------------------------------------------------------------------
+
+    /// Incoming publish on a topic
+    fn publish(topic: &str, payload: &str) -> Publish {
+        Publish::new(topic, QoS::AtLeastOnce, payload)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn messages_reach_every_matching_route_with_their_captures() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let seen = seen.clone();
+            move |message: TopicMessage| {
+                seen.lock().unwrap().push((name, message.captures));
+                std::future::ready(())
+            }
+        };
+        let mut dispatcher = TopicDispatcher::new("pza");
+        dispatcher
+            .route("+/cmd/#", record("cmd"))
+            .route("#", record("all"));
+        assert_eq!(dispatcher.filters(), ["pza/+/cmd/#", "pza/#"]);
+
+        assert_eq!(
+            dispatcher
+                .dispatch(&publish("pza/psu/cmd/voltage/set", "1"))
+                .await,
+            2
+        );
+        assert_eq!(dispatcher.dispatch(&publish("pza/psu/att", "1")).await, 1);
+        // Outside of the prefix
+        assert_eq!(
+            dispatcher.dispatch(&publish("other/psu/cmd/x", "1")).await,
+            0
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("cmd", vec!["psu".to_string(), "voltage/set".to_string()]),
+                ("all", vec!["psu/cmd/voltage/set".to_string()]),
+                ("all", vec!["psu/att".to_string()]),
+            ]
+        );
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn json_routes_decode_and_never_wait_for_their_receiver() {
+        let mut dispatcher = TopicDispatcher::new("pza");
+        let mut values = dispatcher.route_json::<u32, _>("+/value");
+
+        dispatcher
+            .dispatch(&publish("pza/psu/value", "not json"))
+            .await;
+        // Nobody reads, the extra messages are dropped
+        for value in 0..JSON_ROUTE_CAPACITY * 2 {
+            dispatcher
+                .dispatch(&publish("pza/psu/value", &value.to_string()))
+                .await;
+        }
+
+        assert!(matches!(
+            values.recv().await,
+            Some(Err(JsonError::Deserialize { .. }))
+        ));
+        let message = values.recv().await.unwrap().unwrap();
+        assert_eq!(message.value, 0);
+        assert_eq!(message.captures, ["psu"]);
+        let mut received = 2;
+        while values.receiver.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, JSON_ROUTE_CAPACITY);
+    }
+}
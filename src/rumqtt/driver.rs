@@ -0,0 +1,558 @@
+use crate::rumqtt::client::SubscribeError;
+use crate::rumqtt::client::SubscribeFailure;
+use crate::rumqtt::client::STATUS_OFFLINE;
+use crate::rumqtt::client::STATUS_ONLINE;
+use rand::Rng;
+use rumqttc::AsyncClient;
+use rumqttc::ClientError;
+use rumqttc::Event;
+use rumqttc::EventLoop;
+use rumqttc::Outgoing;
+use rumqttc::Packet;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use rumqttc::SubAck;
+use rumqttc::SubscribeFilter;
+use rumqttc::SubscribeReasonCode;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// Capacity of the channel delivering the incoming publishes
+const INCOMING_CAPACITY: usize = 100;
+
+/// Time given to the event loop to send the DISCONNECT packet on stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// ===============================================================================
+
+/// Delays between the reconnection attempts of the driver
+///
+/// The delay doubles (by default) after each failed attempt, up to `max_delay`,
+/// and is randomized by `jitter` to avoid all the clients reconnecting at once
+/// when the broker comes back.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnection attempt
+    pub initial_delay: Duration,
+
+    /// Upper bound of the delay
+    pub max_delay: Duration,
+
+    /// Factor applied to the delay after each failed attempt
+    pub multiplier: f64,
+
+    /// Random variation of the delay, as a fraction of it (0.2 is +/- 20%)
+    pub jitter: f64,
+}
+
+// -------------------------------------------------------------------------------
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+impl ReconnectPolicy {
+    /// Delay before the reconnection attempt `attempt`, starting at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = match jitter > 0.0 {
+            true => rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter),
+            false => 1.0,
+        };
+        Duration::from_secs_f64(base * factor)
+    }
+}
+
+// ===============================================================================
+
+/// Connection state of a driven client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// First connection in progress
+    Connecting,
+
+    /// Connected to the broker
+    Connected,
+
+    /// Connection lost, waiting before the reconnection attempt `attempt`
+    Reconnecting {
+        /// Number of the next attempt, starting at 1
+        attempt: u32,
+    },
+
+    /// The driver is stopped
+    Stopped,
+}
+
+// ===============================================================================
+
+/// Sender of the return codes of a SUBACK
+type SubAckSender = oneshot::Sender<Vec<SubscribeReasonCode>>;
+
+// ===============================================================================
+
+/// SUBSCRIBE requests waiting for their SUBACK
+///
+/// rumqttc does not give the packet id of a request to the caller. The
+/// requests are sent in order and rumqttc reports their packet id in the same
+/// order, so the waiters are queued until their packet id is known.
+#[derive(Default)]
+struct SubAckWaiters {
+    /// Requests sent to the event loop, in order, with their optional waiter
+    sent: VecDeque<Option<SubAckSender>>,
+
+    /// Waiters by packet id, once the request is written
+    by_pkid: HashMap<u16, SubAckSender>,
+}
+
+// ===============================================================================
+
+/// Sends the subscriptions of the driver and keeps track of them
+#[derive(Clone)]
+struct Subscriber {
+    /// Client of the driven event loop
+    client: AsyncClient,
+
+    /// Subscriptions to restore on reconnection, by topic filter
+    subscriptions: Arc<Mutex<HashMap<String, QoS>>>,
+
+    /// Requests waiting for their SUBACK
+    waiters: Arc<Mutex<SubAckWaiters>>,
+
+    /// Keeps the order of the requests and of the queued waiters the same
+    send_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl Subscriber {
+    /// Send a SUBSCRIBE request, with a waiter for its SUBACK if given
+    async fn send(
+        &self,
+        filters: Vec<SubscribeFilter>,
+        waiter: Option<SubAckSender>,
+    ) -> Result<(), ClientError> {
+        let _guard = self.send_lock.lock().await;
+        self.waiters.lock().unwrap().sent.push_back(waiter);
+        let result = self.client.subscribe_many(filters).await;
+        if result.is_err() {
+            // Not queued, so no packet id will be reported for it
+            self.waiters.lock().unwrap().sent.pop_back();
+        }
+        result
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Record the packet id of the oldest request
+    fn on_outgoing_subscribe(&self, pkid: u16) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if let Some(Some(waiter)) = waiters.sent.pop_front() {
+            waiters.by_pkid.insert(pkid, waiter);
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Give the return codes of a SUBACK to its waiter
+    fn on_suback(&self, suback: SubAck) {
+        if let Some(waiter) = self.waiters.lock().unwrap().by_pkid.remove(&suback.pkid) {
+            let _ = waiter.send(suback.return_codes);
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Fail the requests written on a lost connection
+    fn on_connection_lost(&self) {
+        self.waiters.lock().unwrap().by_pkid.clear();
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Send the subscriptions again after a connection without session
+    fn resubscribe(&self) {
+        let filters: Vec<SubscribeFilter> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(topic, qos)| SubscribeFilter::new(topic.clone(), *qos))
+            .collect();
+        if filters.is_empty() {
+            return;
+        }
+        debug!("Subscribe again to {} topics", filters.len());
+
+        // Sent from another task, the event loop must keep being polled
+        let subscriber = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = subscriber.send(filters, None).await {
+                warn!("Unable to subscribe again: {}", e);
+            }
+        });
+    }
+}
+
+// ===============================================================================
+
+/// Runs the event loop of a client on tokio
+///
+/// The driver reconnects with the backoff of its [`ReconnectPolicy`] and
+/// subscribes again to the topics subscribed through [`MqttDriver::subscribe`]
+/// when the broker did not keep the session. The incoming publishes are
+/// delivered on the channel returned by [`MqttDriver::spawn`], which holds
+/// up to 100 of them: the following ones are dropped with a warning until the
+/// receiver catches up, so the event loop is never blocked by it.
+///
+/// To match the SUBACK packets with [`MqttDriver::subscribe_many`], all the
+/// subscriptions must go through the driver, not through the raw client.
+pub struct MqttDriver {
+    /// Sends and tracks the subscriptions
+    subscriber: Subscriber,
+
+    /// Connection state
+    state: watch::Receiver<ConnectionState>,
+
+    /// Liveness topic of the client
+    status_topic: Option<String>,
+
+    /// Task polling the event loop
+    task: Option<JoinHandle<()>>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl MqttDriver {
+    /// Spawn the task polling the event loop on the current tokio runtime
+    pub fn spawn(
+        client: AsyncClient,
+        event_loop: EventLoop,
+        policy: ReconnectPolicy,
+    ) -> (Self, mpsc::Receiver<Publish>) {
+        Self::spawn_with_status(client, event_loop, policy, None)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Spawn the driver of a client exposing its liveness on a status topic
+    ///
+    /// A retained "online" message is published on the status topic on each
+    /// connection, and "offline" on [`MqttDriver::stop`]. The client should
+    /// have the matching last will, see `MqttClientBuilder::liveness`.
+    pub fn spawn_with_status(
+        client: AsyncClient,
+        event_loop: EventLoop,
+        policy: ReconnectPolicy,
+        status_topic: Option<String>,
+    ) -> (Self, mpsc::Receiver<Publish>) {
+        let subscriber = Subscriber {
+            client,
+            subscriptions: Default::default(),
+            waiters: Default::default(),
+            send_lock: Default::default(),
+        };
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting);
+        let (incoming_tx, incoming_rx) = mpsc::channel(INCOMING_CAPACITY);
+
+        let task = tokio::spawn(drive(
+            event_loop,
+            policy,
+            subscriber.clone(),
+            status_topic.clone(),
+            state_tx,
+            incoming_tx,
+        ));
+
+        let driver = Self {
+            subscriber,
+            state,
+            status_topic,
+            task: Some(task),
+        };
+        (driver, incoming_rx)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Client of the driven event loop
+    pub fn client(&self) -> &AsyncClient {
+        &self.subscriber.client
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Receiver of the connection state changes
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// True if the client is connected to the broker
+    pub fn is_connected(&self) -> bool {
+        *self.state.borrow() == ConnectionState::Connected
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Subscribe to a topic filter, again after each reconnection if needed
+    pub async fn subscribe<A: Into<String>>(&self, topic: A, qos: QoS) -> Result<(), ClientError> {
+        let topic = topic.into();
+        self.subscriber
+            .subscriptions
+            .lock()
+            .unwrap()
+            .insert(topic.clone(), qos);
+        self.subscriber
+            .send(vec![SubscribeFilter::new(topic, qos)], None)
+            .await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Subscribe to many topic filters with a single SUBSCRIBE packet and wait
+    /// for the SUBACK
+    ///
+    /// Returns the QoS granted for each filter. The filters denied by the
+    /// broker are reported as [`SubscribeFailure::Denied`] and are not
+    /// subscribed again after a reconnection. Use a timeout to bound the wait
+    /// on a broker that does not answer.
+    pub async fn subscribe_many<A: Into<String>>(
+        &self,
+        topics: Vec<(A, QoS)>,
+    ) -> Result<Vec<(String, QoS)>, SubscribeError> {
+        let topics: Vec<(String, QoS)> = topics
+            .into_iter()
+            .map(|(topic, qos)| (topic.into(), qos))
+            .collect();
+        let filters = topics
+            .iter()
+            .map(|(topic, qos)| SubscribeFilter::new(topic.clone(), *qos))
+            .collect();
+
+        let (waiter, suback) = oneshot::channel();
+        if self.subscriber.send(filters, Some(waiter)).await.is_err() {
+            let failures = topics
+                .into_iter()
+                .map(|(topic, _)| (topic, SubscribeFailure::NotSent))
+                .collect();
+            return Err(SubscribeError { failures });
+        }
+
+        let return_codes = match suback.await {
+            Ok(return_codes) => return_codes,
+            Err(_) => {
+                let failures = topics
+                    .into_iter()
+                    .map(|(topic, _)| (topic, SubscribeFailure::NoAck))
+                    .collect();
+                return Err(SubscribeError { failures });
+            }
+        };
+
+        let mut granted = Vec::new();
+        let mut failures = Vec::new();
+        let mut subscriptions = self.subscriber.subscriptions.lock().unwrap();
+        for (i, (topic, qos)) in topics.into_iter().enumerate() {
+            match return_codes.get(i) {
+                Some(SubscribeReasonCode::Success(granted_qos)) => {
+                    subscriptions.insert(topic.clone(), qos);
+                    granted.push((topic, *granted_qos));
+                }
+                Some(SubscribeReasonCode::Failure) => {
+                    failures.push((topic, SubscribeFailure::Denied));
+                }
+                None => failures.push((topic, SubscribeFailure::NoAck)),
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(granted),
+            false => Err(SubscribeError { failures }),
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Unsubscribe from a topic filter
+    pub async fn unsubscribe<A: Into<String>>(&self, topic: A) -> Result<(), ClientError> {
+        let topic = topic.into();
+        self.subscriber.subscriptions.lock().unwrap().remove(&topic);
+        self.subscriber.client.unsubscribe(topic).await
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Disconnect from the broker and stop the driver
+    pub async fn stop(mut self) {
+        if let Some(mut task) = self.task.take() {
+            if let Some(status_topic) = &self.status_topic {
+                let _ = self
+                    .subscriber
+                    .client
+                    .publish(status_topic, QoS::AtLeastOnce, true, STATUS_OFFLINE)
+                    .await;
+            }
+            if self.subscriber.client.disconnect().await.is_ok()
+                && tokio::time::timeout(STOP_TIMEOUT, &mut task).await.is_ok()
+            {
+                return;
+            }
+            task.abort();
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+impl Drop for MqttDriver {
+    /// Stop polling the event loop without disconnecting
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+/// Poll the event loop until the client disconnects
+async fn drive(
+    mut event_loop: EventLoop,
+    policy: ReconnectPolicy,
+    subscriber: Subscriber,
+    status_topic: Option<String>,
+    state: watch::Sender<ConnectionState>,
+    incoming: mpsc::Sender<Publish>,
+) {
+    let mut attempt = 0;
+    // The subscriptions of the first connection are sent by the event loop
+    let mut reconnection = false;
+    loop {
+        match event_loop.poll().await {
+            // Refused connections are reported as errors by rumqttc
+            Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                info!("MQTT client connected");
+                attempt = 0;
+                state.send_replace(ConnectionState::Connected);
+                if reconnection && !connack.session_present {
+                    subscriber.resubscribe();
+                }
+                reconnection = true;
+                if let Some(status_topic) = &status_topic {
+                    // Birth message, replaces the retained last will
+                    if let Err(e) = subscriber.client.try_publish(
+                        status_topic,
+                        QoS::AtLeastOnce,
+                        true,
+                        STATUS_ONLINE,
+                    ) {
+                        warn!("Unable to publish status on '{}': {}", status_topic, e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                // Waiting for the receiver would stop the keep alive and the
+                // acknowledgements, so a slow receiver loses publishes. Nobody
+                // listening is not an error.
+                if let Err(mpsc::error::TrySendError::Full(publish)) = incoming.try_send(publish) {
+                    warn!(
+                        "Incoming publish on '{}' dropped, its receiver is too slow",
+                        publish.topic
+                    );
+                }
+            }
+            Ok(Event::Incoming(Packet::SubAck(suback))) => subscriber.on_suback(suback),
+            Ok(Event::Outgoing(Outgoing::Subscribe(pkid))) => {
+                subscriber.on_outgoing_subscribe(pkid)
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                info!("MQTT client disconnected");
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                subscriber.on_connection_lost();
+                attempt += 1;
+                let delay = policy.delay(attempt);
+                warn!(
+                    "MQTT connection error: {}, reconnecting in {:?} (attempt {})",
+                    e, delay, attempt
+                );
+                state.send_replace(ConnectionState::Reconnecting { attempt });
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+    state.send_replace(ConnectionState::Stopped);
+}
+
+// ===============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.2,
+        };
+        for attempt in 1..200 {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(800), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(1200), "{:?}", delay);
+        }
+
+        // Out of range jitters are clamped
+        let policy = ReconnectPolicy {
+            jitter: 5.0,
+            ..policy
+        };
+        assert!(policy.delay(1) <= Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::rumqtt::broker::local::LocalClientError;
+use crate::rumqtt::dispatch::TopicMessage;
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+// ===============================================================================
+
+/// Error of the JSON helpers
+#[derive(Debug, thiserror::Error)]
+pub enum JsonError {
+    /// The value could not be serialized
+    #[error("unable to serialize payload: {0}")]
+    Serialize(#[source] serde_json::Error),
+
+    /// The payload received on a topic is not a valid JSON value of the
+    /// expected type
+    #[error("malformed payload on '{topic}': {source}")]
+    Deserialize {
+        /// Topic of the message
+        topic: String,
+        /// Parsing error
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The message could not be published
+    #[error("unable to publish: {0}")]
+    Client(#[from] rumqttc::ClientError),
+
+    /// The message could not be published by a local client
+    #[error("unable to publish: {0}")]
+    Local(#[from] LocalClientError),
+}
+
+// ===============================================================================
+
+/// Message whose payload was parsed as JSON
+#[derive(Clone, Debug)]
+pub struct JsonMessage<T> {
+    /// Full topic of the message
+    pub topic: String,
+
+    /// Topic levels matched by the wildcards of the route filter
+    pub captures: Vec<String>,
+
+    /// Parsed payload
+    pub value: T,
+}
+
+// -------------------------------------------------------------------------------
+
+/// Parse the payload of a routed message
+pub fn decode_json<T: DeserializeOwned>(
+    message: TopicMessage,
+) -> Result<JsonMessage<T>, JsonError> {
+    match serde_json::from_slice(&message.payload) {
+        Ok(value) => Ok(JsonMessage {
+            topic: message.topic,
+            captures: message.captures,
+            value,
+        }),
+        Err(source) => Err(JsonError::Deserialize {
+            topic: message.topic,
+            source,
+        }),
+    }
+}
+
+// ===============================================================================
+
+/// Typed messages of a JSON route, see `TopicDispatcher::route_json`
+pub struct JsonSubscription<T> {
+    /// Messages parsed by the route
+    pub(crate) receiver: mpsc::Receiver<Result<JsonMessage<T>, JsonError>>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl<T> JsonSubscription<T> {
+    /// Wait for the next message, `None` once the dispatcher is dropped
+    ///
+    /// Malformed payloads are reported as [`JsonError::Deserialize`] and the
+    /// subscription goes on.
+    pub async fn recv(&mut self) -> Option<Result<JsonMessage<T>, JsonError>> {
+        self.receiver.recv().await
+    }
+}
@@ -0,0 +1,602 @@
+use crate::config::MqttOfflineQueueConfig;
+use crate::config::MqttQueueDropPolicy;
+use crate::rumqtt::client::RumqttCustomAsyncClient;
+use crate::rumqtt::driver::ConnectionState;
+use crate::rumqtt::publish::PublishOptions;
+use crate::rumqtt::util::load_json;
+use crate::rumqtt::util::now_ms;
+use crate::rumqtt::util::qos_from_level;
+use crate::rumqtt::util::write_atomic;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// Maximum number of queued messages when not configured
+const DEFAULT_MAX_MESSAGES: usize = 10_000;
+
+/// Maximum total payload size when not configured
+const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+/// Number of changes of the queue between two writes of its file
+const PERSIST_BATCH: usize = 100;
+
+/// Maximum time a change of the queue waits before being written to its file
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time waited before publishing a message again after a failure
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// ===============================================================================
+
+/// Error of a publish through the offline queue
+#[derive(Debug, thiserror::Error)]
+pub enum OfflineQueueError {
+    /// The queue is full and its policy rejects new messages
+    #[error("offline queue full")]
+    Full,
+
+    /// The message alone is larger than the queue
+    #[error("message of {0} bytes larger than the offline queue")]
+    TooLarge(usize),
+}
+
+// ===============================================================================
+
+/// Message waiting for the connection
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct QueuedMessage {
+    /// Topic of the message
+    topic: String,
+
+    /// Payload of the message
+    payload: Vec<u8>,
+
+    /// Quality of service, as its MQTT level
+    qos: u8,
+
+    /// Retain flag
+    retain: bool,
+
+    /// Time the message was queued, in milliseconds since the Unix epoch
+    queued_at_ms: u64,
+}
+
+// ===============================================================================
+
+/// Messages of the queue and its limits
+struct QueueState {
+    /// Messages, oldest first
+    messages: VecDeque<QueuedMessage>,
+
+    /// Total size of the queued payloads
+    bytes: usize,
+
+    /// Maximum number of messages
+    max_messages: usize,
+
+    /// Maximum total size of the payloads
+    max_bytes: usize,
+
+    /// Maximum age of the messages
+    max_age: Option<Duration>,
+
+    /// Behaviour when full
+    drop_policy: MqttQueueDropPolicy,
+
+    /// File the queue is persisted to
+    file: Option<PathBuf>,
+
+    /// Number of changes not written to the file yet
+    unsaved: usize,
+}
+
+// -------------------------------------------------------------------------------
+
+impl QueueState {
+    /// Add a message, applying the limits
+    fn push(&mut self, message: QueuedMessage) -> Result<(), OfflineQueueError> {
+        self.drop_expired();
+        let size = message.payload.len();
+        if size > self.max_bytes {
+            return Err(OfflineQueueError::TooLarge(size));
+        }
+        while self.messages.len() >= self.max_messages || self.bytes + size > self.max_bytes {
+            match self.drop_policy {
+                MqttQueueDropPolicy::RejectNew => return Err(OfflineQueueError::Full),
+                MqttQueueDropPolicy::DropOldest => {
+                    if let Some(dropped) = self.pop() {
+                        debug!("Offline queue full, message on '{}' dropped", dropped.topic);
+                    }
+                }
+            }
+        }
+        self.bytes += size;
+        self.messages.push_back(message);
+        self.unsaved += 1;
+        Ok(())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Remove the oldest message
+    fn pop(&mut self) -> Option<QueuedMessage> {
+        let message = self.messages.pop_front()?;
+        self.bytes -= message.payload.len();
+        self.unsaved += 1;
+        Some(message)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Copy of the oldest message that has not expired, left in the queue
+    fn peek_valid(&mut self) -> Option<QueuedMessage> {
+        self.drop_expired();
+        self.messages.front().cloned()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Remove a message accepted by the client, unless it was dropped meanwhile
+    fn remove_published(&mut self, message: &QueuedMessage) {
+        if self.messages.front() == Some(message) {
+            self.pop();
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Remove the messages older than the maximum age
+    fn drop_expired(&mut self) {
+        let Some(max_age) = self.max_age else {
+            return;
+        };
+        let oldest_allowed = now_ms().saturating_sub(max_age.as_millis() as u64);
+        while let Some(message) = self.messages.front() {
+            if message.queued_at_ms >= oldest_allowed {
+                break;
+            }
+            debug!("Offline message on '{}' expired", message.topic);
+            self.pop();
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Write the queue into its file once enough changes are pending
+    fn persist_batch(&mut self) {
+        if self.unsaved >= PERSIST_BATCH {
+            self.persist();
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Write the pending changes of the queue into its file, if any
+    fn persist(&mut self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        if self.unsaved == 0 {
+            return;
+        }
+        let result = serde_json::to_vec(&self.messages)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| write_atomic(file, &content));
+        match result {
+            Ok(()) => self.unsaved = 0,
+            Err(e) => warn!(
+                "Unable to persist offline queue to {}: {}",
+                file.display(),
+                e
+            ),
+        }
+    }
+}
+
+// ===============================================================================
+
+/// Publishes through a client, queueing the messages while it is disconnected
+///
+/// The queued messages are published in order once the connection state of the
+/// driver goes back to connected. With a file configured, the queue survives
+/// restarts of the application: the file is written by batches, so a crash
+/// may lose the last queued messages or publish the last sent ones twice.
+///
+/// A message leaves the queue once the client accepted it, not on its PUBACK,
+/// which rumqttc does not report per publish. Until it is acknowledged, the
+/// message only lives in the event loop of the client: it is lost if the
+/// application stops, or if the connection drops and the broker does not
+/// resume the session, which is the case with a clean session. Delivery is
+/// at most once in this window.
+pub struct OfflinePublisher {
+    /// Client used to publish
+    client: RumqttCustomAsyncClient,
+
+    /// Connection state of the client, from its driver
+    state: watch::Receiver<ConnectionState>,
+
+    /// Queued messages
+    queue: Arc<Mutex<QueueState>>,
+
+    /// Wakes the flush task up when a message is queued
+    queued: Arc<Notify>,
+
+    /// Task publishing the queued messages
+    task: JoinHandle<()>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl OfflinePublisher {
+    /// Create the publisher and load the persisted queue
+    ///
+    /// `state` is the connection state of the driver of the client. Must be
+    /// called from a tokio runtime.
+    pub fn new(
+        client: RumqttCustomAsyncClient,
+        state: watch::Receiver<ConnectionState>,
+        config: &MqttOfflineQueueConfig,
+    ) -> anyhow::Result<Self> {
+        let file = match &config.file {
+            Some(_) => Some(
+                config
+                    .resolved_file()
+                    .ok_or_else(|| anyhow::anyhow!("Unable to determine home directory"))?,
+            ),
+            None => None,
+        };
+
+        let mut queue = QueueState {
+            messages: VecDeque::new(),
+            bytes: 0,
+            max_messages: config.max_messages.unwrap_or(DEFAULT_MAX_MESSAGES),
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_age: config.max_age_secs.map(Duration::from_secs),
+            drop_policy: config.drop_policy.unwrap_or_default(),
+            file,
+            unsaved: 0,
+        };
+        if let Some(file) = &queue.file {
+            let messages: Vec<QueuedMessage> = load_json(file)?.unwrap_or_default();
+            if !messages.is_empty() {
+                info!(
+                    "{} offline messages loaded from {}",
+                    messages.len(),
+                    file.display()
+                );
+            }
+            for message in messages {
+                // Limits may have changed since the queue was saved
+                let _ = queue.push(message);
+            }
+            queue.unsaved = 0;
+        }
+
+        let queue = Arc::new(Mutex::new(queue));
+        let queued = Arc::new(Notify::new());
+        let task = tokio::spawn(flush(
+            client.clone(),
+            state.clone(),
+            queue.clone(),
+            queued.clone(),
+        ));
+
+        Ok(Self {
+            client,
+            state,
+            queue,
+            queued,
+            task,
+        })
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish a message, or queue it if the client is disconnected
+    pub fn publish<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+    ) -> Result<(), OfflineQueueError> {
+        self.publish_with(topic, payload, PublishOptions::default())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Publish a message with options, or queue it if the client is disconnected
+    pub fn publish_with<A: Into<String>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+        options: PublishOptions,
+    ) -> Result<(), OfflineQueueError> {
+        let topic = topic.into();
+        let payload = payload.into();
+        let (qos, retain) = self.client.resolve_options(&topic, options);
+
+        let mut queue = self.queue.lock().unwrap();
+
+        // Direct publish only if it keeps the order of the messages
+        let connected = *self.state.borrow() == ConnectionState::Connected;
+        if connected && queue.messages.is_empty() {
+            match self
+                .client
+                .client
+                .try_publish(topic.clone(), qos, retain, payload.clone())
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("Publish on '{}' queued: {}", topic, e),
+            }
+        }
+
+        queue.push(QueuedMessage {
+            topic,
+            payload,
+            qos: qos as u8,
+            retain,
+            queued_at_ms: now_ms(),
+        })?;
+        queue.persist_batch();
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Number of queued messages
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().messages.len()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// True if no message is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+impl Drop for OfflinePublisher {
+    /// Stop publishing the queued messages, they stay in the queue file
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.persist();
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+/// Publish the queued messages each time the client is connected
+///
+/// A message leaves the queue only once the client accepted it, a failed
+/// publish is retried. Its acknowledgement is not waited for, see
+/// [`OfflinePublisher`].
+async fn flush(
+    client: RumqttCustomAsyncClient,
+    mut state: watch::Receiver<ConnectionState>,
+    queue: Arc<Mutex<QueueState>>,
+    queued: Arc<Notify>,
+) {
+    let mut persist = tokio::time::interval(PERSIST_INTERVAL);
+    persist.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let connected = *state.borrow() == ConnectionState::Connected;
+        let next = if connected {
+            queue.lock().unwrap().peek_valid()
+        } else {
+            None
+        };
+        match next {
+            Some(message) => {
+                let qos = qos_from_level(message.qos);
+                match client
+                    .client
+                    .publish(
+                        message.topic.clone(),
+                        qos,
+                        message.retain,
+                        message.payload.clone(),
+                    )
+                    .await
+                {
+                    Ok(()) => {
+                        let mut queue = queue.lock().unwrap();
+                        queue.remove_published(&message);
+                        if queue.messages.is_empty() {
+                            info!("Offline queue flushed");
+                            queue.persist();
+                        } else {
+                            queue.persist_batch();
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Unable to publish offline message: {}", e);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            }
+            None => tokio::select! {
+                _ = queued.notified() => {}
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        // Driver dropped
+                        queue.lock().unwrap().persist();
+                        return;
+                    }
+                }
+                _ = persist.tick() => queue.lock().unwrap().persist(),
+            },
+        }
+    }
+}
+
+// ===============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::AsyncClient;
+    use rumqttc::MqttOptions;
+    use rumqttc::QoS;
+
+    // ---------------------------------------------------------------------------
+
+    /// Empty queue in memory with limits
+    fn queue(
+        max_messages: usize,
+        max_bytes: usize,
+        drop_policy: MqttQueueDropPolicy,
+    ) -> QueueState {
+        QueueState {
+            messages: VecDeque::new(),
+            bytes: 0,
+            max_messages,
+            max_bytes,
+            max_age: None,
+            drop_policy,
+            file: None,
+            unsaved: 0,
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Message queued now
+    fn message(topic: &str, payload: &[u8]) -> QueuedMessage {
+        QueuedMessage {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos: 1,
+            retain: false,
+            queued_at_ms: now_ms(),
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Topics of the queued messages, oldest first
+    fn topics(queue: &QueueState) -> Vec<&str> {
+        queue.messages.iter().map(|m| m.topic.as_str()).collect()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn full_queue_drops_the_oldest_messages() {
+        let mut queue = queue(2, 10, MqttQueueDropPolicy::DropOldest);
+        queue.push(message("a", b"1")).unwrap();
+        queue.push(message("b", b"2")).unwrap();
+        queue.push(message("c", b"3")).unwrap();
+        assert_eq!(topics(&queue), ["b", "c"]);
+
+        // The size limit drops as many messages as needed
+        queue.push(message("d", b"1234567890")).unwrap();
+        assert_eq!(topics(&queue), ["d"]);
+        assert_eq!(queue.bytes, 10);
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn full_queue_rejects_new_messages() {
+        let mut queue = queue(2, 10, MqttQueueDropPolicy::RejectNew);
+        queue.push(message("a", b"1")).unwrap();
+        queue.push(message("b", b"2")).unwrap();
+        assert!(matches!(
+            queue.push(message("c", b"3")),
+            Err(OfflineQueueError::Full)
+        ));
+        assert_eq!(topics(&queue), ["a", "b"]);
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn messages_larger_than_the_queue_are_refused() {
+        let mut queue = queue(2, 4, MqttQueueDropPolicy::DropOldest);
+        queue.push(message("a", b"1")).unwrap();
+        assert!(matches!(
+            queue.push(message("b", b"12345")),
+            Err(OfflineQueueError::TooLarge(5))
+        ));
+        // Nothing dropped for a message that could never fit
+        assert_eq!(topics(&queue), ["a"]);
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn expired_messages_are_never_published() {
+        let mut queue = queue(10, 100, MqttQueueDropPolicy::DropOldest);
+        queue.max_age = Some(Duration::from_secs(60));
+        let mut old = message("old", b"1");
+        old.queued_at_ms -= 120_000;
+        queue.messages.push_back(old);
+        queue.bytes = 1;
+        queue.push(message("new", b"2")).unwrap();
+
+        let next = queue.peek_valid().unwrap();
+        assert_eq!(next.topic, "new");
+        assert_eq!(queue.bytes, 1);
+
+        queue.remove_published(&next);
+        assert!(queue.peek_valid().is_none());
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn queue_is_reloaded_from_its_file() {
+        let file = std::env::temp_dir().join(format!(
+            "pza-toolkit-offline-queue-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        let config = MqttOfflineQueueConfig {
+            file: Some(file.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        // Never connected, every publish is queued
+        let (client, _event_loop) =
+            AsyncClient::new(MqttOptions::new("test-offline", "127.0.0.1", 1883), 10);
+        let client = RumqttCustomAsyncClient::new(client, QoS::AtLeastOnce, false, "pza".into());
+        let (_state_tx, state) = watch::channel(ConnectionState::Connecting);
+
+        let publisher = OfflinePublisher::new(client.clone(), state.clone(), &config).unwrap();
+        publisher.publish("psu/voltage", "3.3").unwrap();
+        publisher
+            .publish_with(
+                "psu/frame",
+                vec![0xff, 0x00],
+                PublishOptions::new().retain(true),
+            )
+            .unwrap();
+        assert_eq!(publisher.len(), 2);
+        drop(publisher);
+
+        let publisher = OfflinePublisher::new(client, state, &config).unwrap();
+        assert_eq!(publisher.len(), 2);
+        let queue = publisher.queue.lock().unwrap();
+        assert_eq!(topics(&queue), ["psu/voltage", "psu/frame"]);
+        assert_eq!(queue.messages[1].payload, [0xff, 0x00]);
+        assert!(queue.messages[1].retain);
+        assert_eq!(queue.bytes, 5);
+        drop(queue);
+        drop(publisher);
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use crate::rumqtt::topic::filter_matches;
+use rumqttc::QoS;
+
+// ===============================================================================
+
+/// QoS and retain settings of a publish, the missing ones fall back to the
+/// client settings
+///
+/// ```ignore
+/// client.publish_with(topic, payload, PublishOptions::new().qos(QoS::AtMostOnce)).await?;
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublishOptions {
+    /// Quality of service
+    pub qos: Option<QoS>,
+
+    /// Retain flag
+    pub retain: Option<bool>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl PublishOptions {
+    /// Options without any override
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the quality of service
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = Some(qos);
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Set the retain flag
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = Some(retain);
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Complete these options with other ones
+    pub fn or(self, other: PublishOptions) -> Self {
+        Self {
+            qos: self.qos.or(other.qos),
+            retain: self.retain.or(other.retain),
+        }
+    }
+}
+
+// ===============================================================================
+
+/// Publish options by topic filter
+///
+/// Filters are relative to the prefix of the client and the first matching
+/// rule applies.
+///
+/// ```ignore
+/// let policy = PublishPolicy::new()
+///     .rule("+/att/#", PublishOptions::new().qos(QoS::AtLeastOnce).retain(true))
+///     .rule("+/stream/#", PublishOptions::new().qos(QoS::AtMostOnce).retain(false));
+/// let client = client.with_policy(policy);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PublishPolicy {
+    /// Topic filters and their options, in order
+    rules: Vec<(String, PublishOptions)>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl PublishPolicy {
+    /// Create an empty policy
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Add a rule, after the existing ones
+    pub fn rule<A: Into<String>>(mut self, filter: A, options: PublishOptions) -> Self {
+        self.rules.push((filter.into(), options));
+        self
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Options of the first rule matching the topic, once prefixed
+    pub fn options_for(&self, prefix: &str, topic: &str) -> PublishOptions {
+        self.rules
+            .iter()
+            .find(|(filter, _)| filter_matches(&format!("{}/{}", prefix, filter), topic))
+            .map(|(_, options)| *options)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,307 @@
+use crate::rand::generate_random_string;
+use crate::rumqtt::client::RumqttCustomAsyncClient;
+use crate::rumqtt::dispatch::TopicDispatcher;
+use crate::rumqtt::dispatch::TopicMessage;
+use bytes::Bytes;
+use rumqttc::Publish;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+// ===============================================================================
+//
+// MQTT 3.1.1 has no response topic, so requests and responses use topic
+// suffixes carrying the requester ID and a sequence number:
+//
+//   request:  {topic}/request/{requester}/{sequence}
+//   response: {topic}/response/{requester}/{sequence}
+//
+// ===============================================================================
+
+/// Error of an RPC request
+#[derive(Debug, thiserror::Error)]
+pub enum RpcError {
+    /// No response received in time
+    #[error("no response after {0:?}")]
+    Timeout(Duration),
+
+    /// The request could not be sent
+    #[error("unable to send the request: {0}")]
+    Client(#[from] rumqttc::ClientError),
+
+    /// The requester was dropped while waiting
+    #[error("requester closed")]
+    Closed,
+}
+
+// ===============================================================================
+
+/// Response waited for, by response topic
+type PendingResponses = Arc<Mutex<HashMap<String, oneshot::Sender<Bytes>>>>;
+
+// ===============================================================================
+
+/// Sends requests and waits for their response
+///
+/// The responses are picked up from the incoming publishes, either through the
+/// channel returned by [`RpcRequester::intercept`] or by giving each publish
+/// to [`RpcRequester::handle_publish`].
+#[derive(Clone)]
+pub struct RpcRequester {
+    /// Client used to send the requests
+    client: RumqttCustomAsyncClient,
+
+    /// Unique ID of the requester, used in the topics
+    requester_id: String,
+
+    /// Sequence number of the next request
+    sequence: Arc<AtomicU64>,
+
+    /// Responses waited for
+    pending: PendingResponses,
+
+    /// Response filters already subscribed to
+    subscribed: Arc<Mutex<HashSet<String>>>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl RpcRequester {
+    /// Create a requester sending its requests with a client
+    pub fn new(client: RumqttCustomAsyncClient) -> Self {
+        Self {
+            client,
+            requester_id: generate_random_string(8),
+            sequence: Arc::new(AtomicU64::new(0)),
+            pending: PendingResponses::default(),
+            subscribed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Send a request on a topic relative to the client prefix, like the ones
+    /// of `route_requests`, and wait for its response payload
+    pub async fn request<A: AsRef<str>, V: Into<Vec<u8>>>(
+        &self,
+        topic: A,
+        payload: V,
+        timeout: Duration,
+    ) -> Result<Bytes, RpcError> {
+        let topic = self.client.topic_with_prefix(topic);
+        let topic = topic.as_str();
+        self.subscribe_responses(topic).await?;
+
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let request_topic = format!("{}/request/{}/{}", topic, self.requester_id, sequence);
+        let response_topic = format!("{}/response/{}/{}", topic, self.requester_id, sequence);
+
+        let (response_tx, response_rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(response_topic.clone(), response_tx);
+
+        if let Err(e) = self
+            .client
+            .client
+            .publish(request_topic, self.client.qos, false, payload)
+            .await
+        {
+            self.pending.lock().unwrap().remove(&response_topic);
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(timeout, response_rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(RpcError::Closed),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&response_topic);
+                Err(RpcError::Timeout(timeout))
+            }
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Complete the request waiting for this publish, returns true if it was a
+    /// response
+    pub fn handle_publish(&self, publish: &Publish) -> bool {
+        match self.pending.lock().unwrap().remove(&publish.topic) {
+            Some(response_tx) => {
+                let _ = response_tx.send(publish.payload.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Pick up the responses from the incoming publishes of a driver, the
+    /// other publishes are given to the returned receiver
+    ///
+    /// Must be called from a tokio runtime.
+    pub fn intercept(&self, mut incoming: mpsc::Receiver<Publish>) -> mpsc::Receiver<Publish> {
+        let (others_tx, others_rx) = mpsc::channel(incoming.max_capacity());
+        let requester = self.clone();
+        tokio::spawn(async move {
+            while let Some(publish) = incoming.recv().await {
+                // Nobody listening for the other publishes is not an error,
+                // the responses are still picked up
+                if !requester.handle_publish(&publish) {
+                    let _ = others_tx.send(publish).await;
+                }
+            }
+        });
+        others_rx
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Subscribe to the responses of a topic, once
+    async fn subscribe_responses(&self, topic: &str) -> Result<(), RpcError> {
+        let filter = format!("{}/response/{}/+", topic, self.requester_id);
+        if self.subscribed.lock().unwrap().contains(&filter) {
+            return Ok(());
+        }
+        self.client
+            .client
+            .subscribe(filter.clone(), self.client.qos)
+            .await?;
+        self.subscribed.lock().unwrap().insert(filter);
+        Ok(())
+    }
+}
+
+// ===============================================================================
+
+/// Answer the requests sent on a topic relative to the dispatcher prefix
+///
+/// The handler returns the response payload, which is published with the
+/// client. Subscribe with `subscribe_routes` once all the routes are set.
+pub fn route_requests<A, F, Fut, V>(
+    dispatcher: &mut TopicDispatcher,
+    client: &RumqttCustomAsyncClient,
+    topic: A,
+    handler: F,
+) where
+    A: AsRef<str>,
+    F: Fn(TopicMessage) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = V> + Send + 'static,
+    V: Into<Vec<u8>> + Send,
+{
+    let topic = topic.as_ref();
+    let client = client.clone();
+    let handler = Arc::new(handler);
+    dispatcher.route(format!("{}/request/+/+", topic), move |message| {
+        let response_topic = response_topic(&message);
+        let client = client.clone();
+        let handler = handler.clone();
+        async move {
+            let response = handler(message).await;
+            if let Err(e) = client
+                .client
+                .publish(response_topic.clone(), client.qos, false, response)
+                .await
+            {
+                warn!("Unable to send response on '{}': {}", response_topic, e);
+            }
+        }
+    });
+}
+
+// -------------------------------------------------------------------------------
+
+/// Response topic of a request, built from its concrete topic
+///
+/// The requester ID and sequence number are the last two captures, the
+/// request topic may have wildcards of its own before them.
+fn response_topic(request: &TopicMessage) -> String {
+    let levels: Vec<&str> = request.topic.split('/').collect();
+    let base = levels[..levels.len().saturating_sub(3)].join("/");
+    let mut captures = request.captures.iter().rev();
+    let sequence = captures.next().map(String::as_str).unwrap_or_default();
+    let requester = captures.next().map(String::as_str).unwrap_or_default();
+    format!("{}/response/{}/{}", base, requester, sequence)
+}
+
+// ===============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::AsyncClient;
+    use rumqttc::MqttOptions;
+    use rumqttc::QoS;
+
+    // ---------------------------------------------------------------------------
+
+    /// Requester whose event loop is never polled, so nothing is answered
+    fn requester() -> (RpcRequester, rumqttc::EventLoop) {
+        let (client, event_loop) =
+            AsyncClient::new(MqttOptions::new("test-rpc", "127.0.0.1", 1883), 10);
+        let client = RumqttCustomAsyncClient::new(client, QoS::AtLeastOnce, false, "pza".into());
+        (RpcRequester::new(client), event_loop)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn responses_go_next_to_the_concrete_request_topic() {
+        let request = TopicMessage {
+            topic: "pza/psu-1/voltage/request/abcd/12".to_string(),
+            captures: vec!["psu-1".to_string(), "abcd".to_string(), "12".to_string()],
+            payload: Bytes::new(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        };
+        assert_eq!(
+            response_topic(&request),
+            "pza/psu-1/voltage/response/abcd/12"
+        );
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn requests_are_answered_on_their_prefixed_response_topic() {
+        let (requester, _event_loop) = requester();
+        let waiting = requester.clone();
+        let response = tokio::spawn(async move {
+            waiting
+                .request("psu/voltage", "3.3", Duration::from_secs(10))
+                .await
+        });
+
+        let response_topic = format!("pza/psu/voltage/response/{}/0", requester.requester_id);
+        let mut publish = Publish::new(&response_topic, QoS::AtLeastOnce, "ok");
+        while !requester.handle_publish(&publish) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(&response.await.unwrap().unwrap()[..], b"ok");
+
+        // Answered once
+        publish.payload = Bytes::from_static(b"again");
+        assert!(!requester.handle_publish(&publish));
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn unanswered_requests_time_out() {
+        let (requester, _event_loop) = requester();
+        let timeout = Duration::from_millis(50);
+        let result = requester.request("psu/voltage", "3.3", timeout).await;
+        assert!(matches!(result, Err(RpcError::Timeout(t)) if t == timeout));
+        assert!(requester.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::rumqtt::topic::filter_matches;
+use crate::rumqtt::util::JsonPayload;
+use bytes::Bytes;
+use rumqttc::Publish;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::RwLock;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+/// Capacity of the change notification channel, slow receivers miss the
+/// oldest changes
+const CHANGES_CAPACITY: usize = 256;
+
+// ===============================================================================
+
+/// Change of a cached topic
+#[derive(Clone, Debug)]
+pub struct StateChange {
+    /// Topic whose value changed
+    pub topic: String,
+
+    /// New value, `None` if the value was cleared by an empty payload
+    pub payload: Option<Bytes>,
+}
+
+// ===============================================================================
+
+/// Last value of every topic matching a set of filters
+///
+/// The cache is fed with the incoming publishes, either through the channel
+/// returned by [`StateCache::intercept`] or by giving each publish to
+/// [`StateCache::handle_publish`]. An empty payload clears the value, like it
+/// clears a retained message. Clones share the same cache.
+#[derive(Clone)]
+pub struct StateCache {
+    /// Filters of the cached topics
+    filters: Vec<String>,
+
+    /// Last value by topic
+    values: Arc<RwLock<BTreeMap<String, Bytes>>>,
+
+    /// Change notifications
+    changes: broadcast::Sender<StateChange>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl StateCache {
+    /// Create an empty cache for topics matching these filters
+    pub fn new(filters: Vec<String>) -> Self {
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+        Self {
+            filters,
+            values: Arc::new(RwLock::new(BTreeMap::new())),
+            changes,
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Filters of the cached topics, to subscribe to
+    pub fn filters(&self) -> &[String] {
+        &self.filters
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Store the payload of a publish, returns true if its topic is cached
+    pub fn handle_publish(&self, publish: &Publish) -> bool {
+        if !self
+            .filters
+            .iter()
+            .any(|filter| filter_matches(filter, &publish.topic))
+        {
+            return false;
+        }
+
+        let payload = match publish.payload.is_empty() {
+            true => None,
+            false => Some(publish.payload.clone()),
+        };
+        let changed = {
+            let mut values = self.values.write().unwrap();
+            match &payload {
+                Some(payload) => {
+                    values.insert(publish.topic.clone(), payload.clone()) != Some(payload.clone())
+                }
+                None => values.remove(&publish.topic).is_some(),
+            }
+        };
+        if changed {
+            // Nobody listening is not an error
+            let _ = self.changes.send(StateChange {
+                topic: publish.topic.clone(),
+                payload,
+            });
+        }
+        true
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Store the incoming publishes of a driver, which are all given to the
+    /// returned receiver afterwards
+    ///
+    /// Must be called from a tokio runtime.
+    pub fn intercept(&self, mut incoming: mpsc::Receiver<Publish>) -> mpsc::Receiver<Publish> {
+        let (forward_tx, forward_rx) = mpsc::channel(incoming.max_capacity());
+        let cache = self.clone();
+        tokio::spawn(async move {
+            while let Some(publish) = incoming.recv().await {
+                cache.handle_publish(&publish);
+                // Nobody listening for the publishes is not an error, the
+                // cache is still fed
+                let _ = forward_tx.send(publish).await;
+            }
+        });
+        forward_rx
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Last value of a topic
+    pub fn get(&self, topic: &str) -> Option<Bytes> {
+        self.values.read().unwrap().get(topic).cloned()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Last values of the topics matching a filter, sorted by topic
+    pub fn query(&self, filter: &str) -> Vec<(String, Bytes)> {
+        self.values
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(topic, _)| filter_matches(filter, topic))
+            .map(|(topic, payload)| (topic.clone(), payload.clone()))
+            .collect()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Receiver of the changes happening from now on
+    pub fn changes(&self) -> broadcast::Receiver<StateChange> {
+        self.changes.subscribe()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Copy of all the values
+    pub fn snapshot(&self) -> BTreeMap<String, Bytes> {
+        self.values.read().unwrap().clone()
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// All the values as a JSON object keyed by topic
+    ///
+    /// Each value is an object with the payload as text in `payload` if valid
+    /// UTF-8, in base64 in `payload_base64` otherwise, like in the capture
+    /// files of the broker recorder.
+    pub fn to_json(&self) -> serde_json::Value {
+        let values = self.values.read().unwrap();
+        let object = values
+            .iter()
+            .map(|(topic, payload)| {
+                let payload = JsonPayload::from(payload.to_vec());
+                (topic.clone(), serde_json::json!(payload))
+            })
+            .collect();
+        serde_json::Value::Object(object)
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Write the JSON snapshot into a file
+    pub fn export_json(&self, path: &Path) -> anyhow::Result<()> {
+        let content = serde_json::to_string_pretty(&self.to_json())?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+// ===============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::QoS;
+
+    // ---------------------------------------------------------------------------
+
+    /// Incoming publish on a topic
+    fn publish(topic: &str, payload: &[u8]) -> Publish {
+        Publish::new(topic, QoS::AtLeastOnce, payload.to_vec())
+    }
+
+    // ---------------------------------------------------------------------------
+
+    /// Cache of the attributes of the prefix `pza`, with some values
+    fn cache() -> StateCache {
+        let cache = StateCache::new(vec!["pza/+/att/#".to_string()]);
+        assert!(cache.handle_publish(&publish("pza/psu/att/voltage", b"3.3")));
+        assert!(cache.handle_publish(&publish("pza/psu/att/current", b"0.1")));
+        assert!(cache.handle_publish(&publish("pza/scope/att/rate", b"1000")));
+        assert!(!cache.handle_publish(&publish("pza/psu/cmd/voltage", b"5")));
+        cache
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn wildcard_queries_return_the_matching_topics_in_order() {
+        let cache = cache();
+        assert_eq!(cache.get("pza/psu/att/voltage").unwrap(), "3.3");
+        assert_eq!(cache.get("pza/psu/cmd/voltage"), None);
+        assert_eq!(
+            cache.query("pza/psu/att/#"),
+            [
+                ("pza/psu/att/current".to_string(), Bytes::from("0.1")),
+                ("pza/psu/att/voltage".to_string(), Bytes::from("3.3")),
+            ]
+        );
+        assert_eq!(cache.query("pza/+/att/rate").len(), 1);
+        assert_eq!(cache.query("#").len(), 3);
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn changes_are_notified_once() {
+        let cache = cache();
+        let mut changes = cache.changes();
+
+        cache.handle_publish(&publish("pza/psu/att/voltage", b"5"));
+        // Same value, no change
+        cache.handle_publish(&publish("pza/psu/att/voltage", b"5"));
+        // An empty payload clears the value
+        cache.handle_publish(&publish("pza/psu/att/current", b""));
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.topic, "pza/psu/att/voltage");
+        assert_eq!(change.payload.unwrap(), "5");
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.topic, "pza/psu/att/current");
+        assert_eq!(change.payload, None);
+        assert!(changes.try_recv().is_err());
+        assert_eq!(cache.get("pza/psu/att/current"), None);
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn export_encodes_payloads_as_text_or_base64() {
+        let cache = cache();
+        cache.handle_publish(&publish("pza/scope/att/frame", &[0xff, 0x00]));
+        let file = std::env::temp_dir().join(format!(
+            "pza-toolkit-state-cache-{}.json",
+            std::process::id()
+        ));
+        cache.export_json(&file).unwrap();
+
+        let exported: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(
+            exported["pza/psu/att/voltage"],
+            serde_json::json!({ "payload": "3.3" })
+        );
+        assert_eq!(
+            exported["pza/scope/att/frame"],
+            serde_json::json!({ "payload_base64": "/wA=" })
+        );
+        assert_eq!(exported.as_object().unwrap().len(), 4);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    // ---------------------------------------------------------------------------
+
+    #[tokio::test]
+    async fn intercepted_publishes_feed_the_cache_and_go_on() {
+        let cache = StateCache::new(vec!["pza/#".to_string()]);
+        let (incoming_tx, incoming) = mpsc::channel(10);
+        let mut forwarded = cache.intercept(incoming);
+
+        incoming_tx
+            .send(publish("pza/psu/att/voltage", b"3.3"))
+            .await
+            .unwrap();
+        let publish = forwarded.recv().await.unwrap();
+        assert_eq!(publish.topic, "pza/psu/att/voltage");
+        assert_eq!(cache.get("pza/psu/att/voltage").unwrap(), "3.3");
+    }
+}
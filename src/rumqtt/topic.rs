@@ -0,0 +1,161 @@
+// =============================================================================
+
+/// True if an MQTT topic filter matches a topic
+///
+/// `+` matches a single level and `#` matches any number of levels, including
+/// the parent one (`a/#` matches `a`). As required by MQTT, filters starting
+/// with a wildcard do not match topics starting with `$`.
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    covers_levels(&levels(filter), &levels(topic))
+}
+
+// -----------------------------------------------------------------------------
+
+/// True if every topic matched by `filter` is also matched by `pattern`
+///
+/// Both arguments are topic filters. `pattern` may use `#` at any level, where
+/// it matches any number of levels (`pza/#/cmd` matches `pza/a/b/cmd`).
+pub fn filter_covers(pattern: &str, filter: &str) -> bool {
+    covers_levels(&levels(pattern), &levels(filter))
+}
+
+// -----------------------------------------------------------------------------
+
+/// True if at least one topic is matched by both filters
+///
+/// Like in [`filter_covers`], `#` can be used at any level.
+pub fn filters_intersect(a: &str, b: &str) -> bool {
+    intersect_levels(&levels(a), &levels(b))
+}
+
+// -----------------------------------------------------------------------------
+
+/// True if the topic filter is valid for a SUBSCRIBE packet
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels = levels(filter);
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains('+') && !level.contains('#'),
+    })
+}
+
+// -----------------------------------------------------------------------------
+
+/// Split a topic or a filter into its levels
+fn levels(topic: &str) -> Vec<&str> {
+    topic.split('/').collect()
+}
+
+// -----------------------------------------------------------------------------
+
+/// Level by level implementation of [`filter_covers`]
+fn covers_levels(pattern: &[&str], filter: &[&str]) -> bool {
+    match (pattern.first(), filter.first()) {
+        (None, None) => true,
+        // '#' either stops here or absorbs one more level of the filter
+        (Some(&"#"), _) => {
+            covers_levels(&pattern[1..], filter)
+                || (!filter.is_empty() && covers_levels(pattern, &filter[1..]))
+        }
+        (_, Some(&"#")) => false,
+        (None, _) | (_, None) => false,
+        (Some(&"+"), Some(_)) => covers_levels(&pattern[1..], &filter[1..]),
+        (Some(p), Some(f)) => p == f && *f != "+" && covers_levels(&pattern[1..], &filter[1..]),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Level by level implementation of [`filters_intersect`]
+fn intersect_levels(a: &[&str], b: &[&str]) -> bool {
+    match (a.first(), b.first()) {
+        (None, None) => true,
+        (Some(&"#"), _) => {
+            intersect_levels(&a[1..], b) || (!b.is_empty() && intersect_levels(a, &b[1..]))
+        }
+        (_, Some(&"#")) => intersect_levels(b, a),
+        (None, _) | (_, None) => false,
+        (Some(x), Some(y)) => {
+            (*x == "+" || *y == "+" || x == y) && intersect_levels(&a[1..], &b[1..])
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Topic levels matched by the wildcards of a filter, `None` if no match
+///
+/// Each `+` captures one level and `#` captures the remaining levels joined
+/// with `/` (an empty string when it matches the parent level).
+pub fn filter_captures(filter: &str, topic: &str) -> Option<Vec<String>> {
+    if !filter_matches(filter, topic) {
+        return None;
+    }
+    let topic_levels = levels(topic);
+    let mut captures = Vec::new();
+    for (i, level) in levels(filter).iter().enumerate() {
+        match *level {
+            "+" => captures.push(topic_levels[i].to_string()),
+            "#" => captures.push(topic_levels.get(i..).unwrap_or_default().join("/")),
+            _ => {}
+        }
+    }
+    Some(captures)
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn filter_covers_wildcards() {
+        assert!(filter_covers("pza/#", "pza/a/b"));
+        assert!(filter_covers("pza/#", "pza/+/cmd"));
+        assert!(filter_covers("pza/#", "pza"));
+        assert!(filter_covers("pza/+/cmd", "pza/a/cmd"));
+        assert!(filter_covers("pza/#/cmd", "pza/a/b/cmd"));
+        assert!(filter_covers("pza/#/cmd", "pza/cmd"));
+        assert!(!filter_covers("pza/+/cmd", "pza/+/att"));
+        assert!(!filter_covers("pza/+/cmd", "pza/#"));
+        assert!(!filter_covers("pza/a", "pza/+"));
+        assert!(!filter_covers("pza/+", "pza/a/b"));
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn filters_intersect_wildcards() {
+        assert!(filters_intersect("pza/+/cmd", "pza/a/#"));
+        assert!(filters_intersect("pza/#", "+/a"));
+        assert!(filters_intersect("pza/#/cmd", "pza/a/b/+"));
+        assert!(filters_intersect("#", "pza/a"));
+        assert!(!filters_intersect("pza/+/cmd", "pza/a/att"));
+        assert!(!filters_intersect("pza/a", "pza/a/b"));
+        assert!(!filters_intersect("pza/#/cmd", "pza/a/att"));
+    }
+
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn filter_captures_levels() {
+        assert_eq!(
+            filter_captures("pza/+/cmd/#", "pza/psu/cmd/a/b"),
+            Some(vec!["psu".to_string(), "a/b".to_string()])
+        );
+        assert_eq!(filter_captures("pza/#", "pza"), Some(vec![String::new()]));
+        assert_eq!(filter_captures("pza/a", "pza/a"), Some(Vec::new()));
+        assert_eq!(filter_captures("pza/+", "pza/a/b"), None);
+        assert_eq!(filter_captures("#", "$SYS/broker"), None);
+    }
+}
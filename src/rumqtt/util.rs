@@ -0,0 +1,145 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rumqttc::QoS;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tracing::warn;
+
+// ===============================================================================
+
+/// Payload written in a JSON file, as text in `payload` if valid UTF-8 and in
+/// base64 in `payload_base64` otherwise
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonPayload {
+    /// Payload, if valid UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+
+    /// Payload in base64, if not valid UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_base64: Option<String>,
+}
+
+// -------------------------------------------------------------------------------
+
+impl From<Vec<u8>> for JsonPayload {
+    fn from(payload: Vec<u8>) -> Self {
+        match String::from_utf8(payload) {
+            Ok(text) => Self {
+                payload: Some(text),
+                payload_base64: None,
+            },
+            Err(e) => Self {
+                payload: None,
+                payload_base64: Some(BASE64.encode(e.as_bytes())),
+            },
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+impl TryFrom<JsonPayload> for Vec<u8> {
+    type Error = String;
+
+    fn try_from(payload: JsonPayload) -> Result<Self, Self::Error> {
+        match (payload.payload, payload.payload_base64) {
+            (Some(text), None) => Ok(text.into_bytes()),
+            (None, Some(encoded)) => BASE64
+                .decode(encoded)
+                .map_err(|e| format!("invalid base64 payload: {}", e)),
+            (None, None) => Ok(Vec::new()),
+            (Some(_), Some(_)) => Err("both payload and payload_base64 are set".to_string()),
+        }
+    }
+}
+
+// ===============================================================================
+
+/// QoS matching an MQTT level, invalid ones fall back to at least once
+pub fn qos_from_level(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+/// Current time in milliseconds since the Unix epoch
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// -------------------------------------------------------------------------------
+
+/// Content of a JSON file, `None` if it does not exist
+///
+/// A file that cannot be parsed is moved aside with a `.corrupt` suffix and
+/// reported as missing, so a damaged file never prevents a start.
+pub fn load_json<T: DeserializeOwned>(file: &Path) -> anyhow::Result<Option<T>> {
+    let content = match std::fs::read(file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match serde_json::from_slice(&content) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => {
+            let corrupt = suffixed(file, ".corrupt");
+            warn!(
+                "File {} unreadable ({}), moved to {}",
+                file.display(),
+                e,
+                corrupt.display()
+            );
+            std::fs::rename(file, corrupt)?;
+            Ok(None)
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------
+
+/// Replace the content of a file through a temporary file, creating its directory
+///
+/// The file is either the previous one or the new one, even if the process or
+/// the system stops while writing: the temporary file is synced before the
+/// rename, and the directory after it.
+pub fn write_atomic(file: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let parent = file
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    if let Some(parent) = parent {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temporary = suffixed(file, ".tmp");
+    let mut written = std::fs::File::create(&temporary)?;
+    written.write_all(content)?;
+    written.sync_all()?;
+    drop(written);
+    std::fs::rename(&temporary, file)?;
+    // Directories cannot be opened as files on Windows
+    #[cfg(unix)]
+    std::fs::File::open(parent.unwrap_or(Path::new(".")))?.sync_all()?;
+    Ok(())
+}
+
+// -------------------------------------------------------------------------------
+
+/// Path of a file with a suffix added to its name
+fn suffixed(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,115 @@
+use pza_toolkit::config::IPEndpointConfig;
+use pza_toolkit::config::MqttBridgeConfig;
+use pza_toolkit::config::MqttBridgeDirection;
+use pza_toolkit::config::MqttBridgeTopicConfig;
+use pza_toolkit::config::MqttBrokerConfig;
+use pza_toolkit::rumqtt::broker::start_broker;
+use pza_toolkit::rumqtt::client::MqttClientBuilder;
+use pza_toolkit::rumqtt::driver::ReconnectPolicy;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Maximum time given to the bridge to connect
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time after which no more message is expected
+const QUIET_TIME: Duration = Duration::from_millis(500);
+
+// =============================================================================
+
+/// Payloads received on a topic until nothing comes for a while
+async fn collect(incoming: &mut mpsc::Receiver<Publish>, topic: &str) -> Vec<String> {
+    let mut payloads = Vec::new();
+    while let Ok(Some(publish)) = tokio::time::timeout(QUIET_TIME, incoming.recv()).await {
+        if publish.topic == topic {
+            payloads.push(String::from_utf8_lossy(&publish.payload).to_string());
+        }
+    }
+    payloads
+}
+
+// -----------------------------------------------------------------------------
+
+/// Two brokers on ephemeral ports, bridged both ways on `bench/#`: messages
+/// cross the bridge once in each direction, repeats included, and never loop
+#[tokio::test(flavor = "multi_thread")]
+async fn bridge_forwards_repeats_without_loops() {
+    let central = start_broker(MqttBrokerConfig::new_ephemeral())
+        .await
+        .unwrap();
+    let central_addr = central.tcp_addr().unwrap();
+
+    let mut bench_config = MqttBrokerConfig::new_ephemeral();
+    bench_config.bridge = Some(MqttBridgeConfig {
+        remote: IPEndpointConfig::from(central_addr),
+        credentials: None,
+        name: Some("bench-bridge".into()),
+        topics: vec![MqttBridgeTopicConfig {
+            filter: "bench/#".into(),
+            direction: MqttBridgeDirection::Both,
+            local_prefix: None,
+            remote_prefix: None,
+        }],
+    });
+    let bench = start_broker(bench_config).await.unwrap();
+
+    let (remote, mut remote_incoming) = MqttClientBuilder::new("test-central")
+        .endpoint(&IPEndpointConfig::from(central_addr))
+        .spawn_driver(ReconnectPolicy::default());
+    remote.subscribe("bench/#", QoS::AtLeastOnce).await.unwrap();
+    let (local, mut local_incoming) = bench.local_client(QoS::AtLeastOnce, false, "bench");
+    local.subscribe("bench/#").await.unwrap();
+
+    // Probe until the bridge is connected
+    let deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "bridge not connected"
+        );
+        local.publish("bench/probe", "probe").await.unwrap();
+        if !collect(&mut remote_incoming, "bench/probe")
+            .await
+            .is_empty()
+        {
+            break;
+        }
+    }
+    collect(&mut local_incoming, "bench/probe").await;
+
+    // Out: the local client receives its own messages, the central broker
+    // receives them once through the bridge
+    local.publish("bench/value", "1").await.unwrap();
+    local.publish("bench/value", "1").await.unwrap();
+    assert_eq!(
+        collect(&mut remote_incoming, "bench/value").await,
+        ["1", "1"]
+    );
+    assert_eq!(
+        collect(&mut local_incoming, "bench/value").await,
+        ["1", "1"]
+    );
+
+    // In: the messages of the central broker reach the local client once, and
+    // do not come back to the central broker
+    for _ in 0..2 {
+        remote
+            .client()
+            .publish("bench/cmd", QoS::AtLeastOnce, false, "on")
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        collect(&mut local_incoming, "bench/cmd").await,
+        ["on", "on"]
+    );
+    assert_eq!(
+        collect(&mut remote_incoming, "bench/cmd").await,
+        ["on", "on"]
+    );
+
+    bench.shutdown().await.unwrap();
+    central.shutdown().await.unwrap();
+}
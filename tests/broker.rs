@@ -0,0 +1,82 @@
+use pza_toolkit::config::MqttBrokerConfig;
+use pza_toolkit::rumqtt::broker::start_broker;
+use rumqttc::AsyncClient;
+use rumqttc::Event;
+use rumqttc::EventLoop;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Number of brokers started at once
+const BROKER_COUNT: usize = 4;
+
+/// Maximum time given to a message to come back
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// =============================================================================
+
+/// Next publish received from the broker
+async fn next_publish(eventloop: &mut EventLoop) -> Publish {
+    loop {
+        let event = tokio::time::timeout(RECEIVE_TIMEOUT, eventloop.poll())
+            .await
+            .expect("no message from the broker")
+            .unwrap();
+        if let Event::Incoming(Packet::Publish(publish)) = event {
+            return publish;
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Brokers started in parallel on ephemeral ports each get their own address
+/// and only serve their own clients
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_ephemeral_brokers_are_isolated() {
+    let starts: Vec<_> = (0..BROKER_COUNT)
+        .map(|_| tokio::spawn(start_broker(MqttBrokerConfig::new_ephemeral())))
+        .collect();
+    let mut brokers = Vec::new();
+    for start in starts {
+        brokers.push(start.await.unwrap().unwrap());
+    }
+
+    let addrs: Vec<_> = brokers
+        .iter()
+        .map(|broker| broker.tcp_addr().unwrap())
+        .collect();
+    assert!(addrs.iter().all(|addr| addr.port() != 0));
+    assert_eq!(addrs.iter().collect::<HashSet<_>>().len(), BROKER_COUNT);
+
+    // Each client publishes its broker index on the same topic
+    let mut clients = Vec::new();
+    for (index, addr) in addrs.iter().enumerate() {
+        let options = MqttOptions::new(
+            format!("test-{}", index),
+            addr.ip().to_string(),
+            addr.port(),
+        );
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        client
+            .subscribe("test/value", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        client
+            .publish("test/value", QoS::AtLeastOnce, false, index.to_string())
+            .await
+            .unwrap();
+        clients.push((client, eventloop));
+    }
+    for (index, (_, eventloop)) in clients.iter_mut().enumerate() {
+        let publish = next_publish(eventloop).await;
+        assert_eq!(publish.payload, index.to_string().as_bytes());
+    }
+
+    for broker in brokers {
+        broker.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use pza_toolkit::config::IPEndpointConfig;
+use pza_toolkit::config::MqttBrokerConfig;
+use pza_toolkit::rumqtt::broker::start_broker;
+use pza_toolkit::rumqtt::client::MqttClientBuilder;
+use pza_toolkit::rumqtt::driver::ConnectionState;
+use pza_toolkit::rumqtt::driver::ReconnectPolicy;
+use rumqttc::AsyncClient;
+use rumqttc::Event;
+use rumqttc::EventLoop;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// More publishes than the channel of the driver holds
+const FLOOD_COUNT: usize = 300;
+
+/// Maximum time given to an exchange with the broker
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// =============================================================================
+
+/// Short delays, so the test does not wait for the reconnections
+fn fast_policy() -> ReconnectPolicy {
+    ReconnectPolicy {
+        initial_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(200),
+        ..Default::default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Plain client of the broker, with its event loop
+fn plain_client(id: &str, addr: SocketAddr) -> (AsyncClient, EventLoop) {
+    let options = MqttOptions::new(id, addr.ip().to_string(), addr.port());
+    AsyncClient::new(options, 10)
+}
+
+// -----------------------------------------------------------------------------
+
+/// Plain client whose event loop is polled in the background
+fn publisher(id: &str, addr: SocketAddr) -> AsyncClient {
+    let (client, mut eventloop) = plain_client(id, addr);
+    tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+    client
+}
+
+// -----------------------------------------------------------------------------
+
+/// Next packet received from the broker
+async fn next_packet(eventloop: &mut EventLoop) -> Packet {
+    loop {
+        let event = tokio::time::timeout(EXCHANGE_TIMEOUT, eventloop.poll())
+            .await
+            .expect("no packet from the broker")
+            .unwrap();
+        if let Event::Incoming(packet) = event {
+            return packet;
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Publish on a topic until the driver receives a message
+async fn publish_until_received(
+    client: &AsyncClient,
+    topic: &str,
+    incoming: &mut tokio::sync::mpsc::Receiver<Publish>,
+) -> Publish {
+    tokio::time::timeout(EXCHANGE_TIMEOUT, async {
+        loop {
+            client
+                .publish(topic, QoS::AtLeastOnce, false, "value")
+                .await
+                .unwrap();
+            if let Ok(Some(publish)) =
+                tokio::time::timeout(Duration::from_millis(100), incoming.recv()).await
+            {
+                break publish;
+            }
+        }
+    })
+    .await
+    .expect("subscription of the driver not active")
+}
+
+// =============================================================================
+
+/// A receiver left behind does not block the event loop, and the
+/// subscriptions come back when the client reconnects to a new broker
+#[tokio::test(flavor = "multi_thread")]
+async fn driver_keeps_polling_and_subscribes_again_after_reconnection() {
+    let broker = start_broker(MqttBrokerConfig::new_ephemeral())
+        .await
+        .unwrap();
+    let addr = broker.tcp_addr().unwrap();
+
+    let (driver, mut incoming) = MqttClientBuilder::new("test-driver")
+        .endpoint(&IPEndpointConfig::from(addr))
+        .spawn_driver(fast_policy());
+    driver
+        .subscribe("test/flood", QoS::AtMostOnce)
+        .await
+        .unwrap();
+    let flooder = publisher("test-flooder", addr);
+    publish_until_received(&flooder, "test/flood", &mut incoming).await;
+
+    // Flood the receiver, which is not read meanwhile
+    for index in 0..FLOOD_COUNT {
+        flooder
+            .publish("test/flood", QoS::AtMostOnce, false, index.to_string())
+            .await
+            .unwrap();
+    }
+    // The publish of the driver only goes out if its event loop is still polled
+    let (probe, mut probe_loop) = plain_client("test-probe", addr);
+    probe
+        .subscribe("test/probe", QoS::AtMostOnce)
+        .await
+        .unwrap();
+    while !matches!(next_packet(&mut probe_loop).await, Packet::SubAck(_)) {}
+    driver
+        .client()
+        .publish("test/probe", QoS::AtMostOnce, false, "alive")
+        .await
+        .unwrap();
+    loop {
+        if let Packet::Publish(publish) = next_packet(&mut probe_loop).await {
+            assert_eq!(&publish.payload[..], b"alive");
+            break;
+        }
+    }
+    while incoming.try_recv().is_ok() {}
+
+    // Restart the broker on the same port, without the session of the client
+    broker.shutdown().await.unwrap();
+    let mut state = driver.state();
+    tokio::time::timeout(
+        EXCHANGE_TIMEOUT,
+        state.wait_for(|state| matches!(state, ConnectionState::Reconnecting { .. })),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let mut config = MqttBrokerConfig::new_ephemeral();
+    config.tcp = Some(IPEndpointConfig::from(addr));
+    let broker = start_broker(config).await.unwrap();
+    tokio::time::timeout(
+        EXCHANGE_TIMEOUT,
+        state.wait_for(|state| *state == ConnectionState::Connected),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // Published until the subscription is restored
+    let restored = publisher("test-restored", addr);
+    let received = publish_until_received(&restored, "test/flood", &mut incoming).await;
+    assert_eq!(received.topic, "test/flood");
+    assert_eq!(&received.payload[..], b"value");
+
+    driver.stop().await;
+    broker.shutdown().await.unwrap();
+}
@@ -0,0 +1,170 @@
+use pza_toolkit::config::MqttAclAction;
+use pza_toolkit::config::MqttAclConfig;
+use pza_toolkit::config::MqttAclPermission;
+use pza_toolkit::config::MqttAclRuleConfig;
+use pza_toolkit::config::MqttBrokerConfig;
+use pza_toolkit::rumqtt::broker::local::LocalClientError;
+use pza_toolkit::rumqtt::broker::start_broker;
+use rumqttc::Publish;
+use rumqttc::QoS;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// More publishes than the channel of a local client holds
+const FLOOD_COUNT: usize = 300;
+
+/// Maximum time given to a message to come
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time after which no more message is expected
+const QUIET_TIME: Duration = Duration::from_millis(500);
+
+// =============================================================================
+
+/// Next message of a local client
+async fn next(incoming: &mut mpsc::Receiver<Publish>) -> Publish {
+    tokio::time::timeout(RECEIVE_TIMEOUT, incoming.recv())
+        .await
+        .expect("no message received")
+        .unwrap()
+}
+
+// -----------------------------------------------------------------------------
+
+/// A client which never reads its receiver loses its messages, the others
+/// still get theirs
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_local_client_does_not_hold_the_others_back() {
+    let broker = start_broker(MqttBrokerConfig::new_ephemeral())
+        .await
+        .unwrap();
+    let (slow, _slow_incoming) = broker.local_client(QoS::AtMostOnce, false, "test");
+    slow.subscribe("test/flood").await.unwrap();
+    let (reader, mut incoming) = broker.local_client(QoS::AtMostOnce, false, "test");
+    reader.subscribe("test/flood").await.unwrap();
+
+    let (publisher, _) = broker.local_client(QoS::AtMostOnce, false, "test");
+    let flood = async {
+        for index in 0..FLOOD_COUNT {
+            publisher
+                .publish("test/flood", index.to_string())
+                .await
+                .unwrap();
+        }
+    };
+    tokio::time::timeout(RECEIVE_TIMEOUT, flood)
+        .await
+        .expect("publisher blocked by a slow local client");
+
+    // Messages beyond the capacity of the reader were dropped, it gets the
+    // next ones once it reads again
+    let mut received = 0;
+    while incoming.try_recv().is_ok() {
+        received += 1;
+    }
+    assert!(received > 0 && received < FLOOD_COUNT);
+    publisher.publish("test/flood", "after").await.unwrap();
+    assert_eq!(&next(&mut incoming).await.payload[..], b"after");
+
+    broker.shutdown().await.unwrap();
+}
+
+// -----------------------------------------------------------------------------
+
+/// Each new subscription receives the retained messages, flagged as retained,
+/// and an empty payload clears them
+#[tokio::test(flavor = "multi_thread")]
+async fn retained_messages_are_given_to_new_subscriptions() {
+    let broker = start_broker(MqttBrokerConfig::new_ephemeral())
+        .await
+        .unwrap();
+    let (publisher, _) = broker.local_client(QoS::AtLeastOnce, true, "test");
+    publisher.publish("test/state/a", "on").await.unwrap();
+    publisher.publish("test/state/b", "off").await.unwrap();
+
+    let (subscriber, mut incoming) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    subscriber.subscribe("test/state/a").await.unwrap();
+    let publish = next(&mut incoming).await;
+    assert_eq!(publish.topic, "test/state/a");
+    assert_eq!(&publish.payload[..], b"on");
+    assert!(publish.retain);
+
+    // Live messages are not flagged
+    publisher.publish("test/state/a", "off").await.unwrap();
+    let publish = next(&mut incoming).await;
+    assert_eq!(&publish.payload[..], b"off");
+    assert!(!publish.retain);
+
+    publisher.publish("test/state/b", "").await.unwrap();
+    let (late, mut late_incoming) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    late.subscribe("test/state/#").await.unwrap();
+    let publish = next(&mut late_incoming).await;
+    assert_eq!(publish.topic, "test/state/a");
+    assert!(tokio::time::timeout(QUIET_TIME, late_incoming.recv())
+        .await
+        .is_err());
+
+    broker.shutdown().await.unwrap();
+}
+
+// -----------------------------------------------------------------------------
+
+/// The ACL sees each local client under its own client id
+#[tokio::test(flavor = "multi_thread")]
+async fn local_clients_have_their_own_acl_identity() {
+    let mut config = MqttBrokerConfig::new_ephemeral();
+    config.acl = Some(MqttAclConfig {
+        default_permission: None,
+        rules: Some(vec![MqttAclRuleConfig {
+            users: None,
+            client_ids: Some(vec!["local-clients-1".to_string()]),
+            prefix: None,
+            action: MqttAclAction::Publish,
+            permission: MqttAclPermission::Deny,
+            topics: vec!["secret/#".to_string()],
+        }]),
+    });
+    let broker = start_broker(config).await.unwrap();
+    let (first, _) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    let (second, _) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    assert_eq!(first.client_id(), "local-clients-0");
+    assert_eq!(second.client_id(), "local-clients-1");
+
+    first.publish("secret/value", "1").await.unwrap();
+    assert!(matches!(
+        second.publish("secret/value", "2").await,
+        Err(LocalClientError::Denied(_))
+    ));
+    second.publish("public/value", "3").await.unwrap();
+
+    broker.shutdown().await.unwrap();
+}
+
+// -----------------------------------------------------------------------------
+
+/// An unsubscribed filter no longer delivers anything, the other clients keep
+/// their subscriptions
+#[tokio::test(flavor = "multi_thread")]
+async fn unsubscribed_filters_deliver_nothing() {
+    let broker = start_broker(MqttBrokerConfig::new_ephemeral())
+        .await
+        .unwrap();
+    let (leaving, mut leaving_incoming) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    leaving.subscribe("test/value").await.unwrap();
+    let (staying, mut staying_incoming) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    staying.subscribe("test/value").await.unwrap();
+
+    let (publisher, _) = broker.local_client(QoS::AtLeastOnce, false, "test");
+    publisher.publish("test/value", "before").await.unwrap();
+    assert_eq!(&next(&mut leaving_incoming).await.payload[..], b"before");
+    assert_eq!(&next(&mut staying_incoming).await.payload[..], b"before");
+
+    leaving.unsubscribe("test/value").await.unwrap();
+    publisher.publish("test/value", "after").await.unwrap();
+    assert_eq!(&next(&mut staying_incoming).await.payload[..], b"after");
+    assert!(tokio::time::timeout(QUIET_TIME, leaving_incoming.recv())
+        .await
+        .is_err());
+
+    broker.shutdown().await.unwrap();
+}
@@ -0,0 +1,136 @@
+use pza_toolkit::config::MqttBrokerConfig;
+use pza_toolkit::config::TlsEndpointConfig;
+use pza_toolkit::rumqtt::broker::start_broker;
+use rcgen::BasicConstraints;
+use rcgen::CertificateParams;
+use rcgen::IsCa;
+use rcgen::KeyPair;
+use rumqttc::AsyncClient;
+use rumqttc::Event;
+use rumqttc::EventLoop;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::QoS;
+use rumqttc::Transport;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Maximum time given to the TLS client to receive a packet
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// =============================================================================
+
+/// Certificate authority and broker certificate, written in a temporary
+/// directory removed on drop
+struct TestCertificates {
+    /// Directory of the files
+    dir: PathBuf,
+
+    /// PEM certificate of the authority, trusted by the client
+    ca_pem: String,
+}
+
+// -----------------------------------------------------------------------------
+
+impl TestCertificates {
+    /// Generate a certificate authority and a broker certificate for localhost
+    fn generate(name: &str) -> Self {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let broker_key = KeyPair::generate().unwrap();
+        let broker = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&broker_key, &ca, &ca_key)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("pza-toolkit-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("broker.pem"), broker.pem()).unwrap();
+        std::fs::write(dir.join("broker.key"), broker_key.serialize_pem()).unwrap();
+        Self {
+            dir,
+            ca_pem: ca.pem(),
+        }
+    }
+
+    // -------------------------------------------------------------------------
+
+    /// TLS endpoint of the broker on a free loopback port
+    fn endpoint(&self) -> TlsEndpointConfig {
+        TlsEndpointConfig {
+            addr: Some("127.0.0.1".into()),
+            port: Some(0),
+            cert_path: Some(self.dir.join("broker.pem").to_string_lossy().to_string()),
+            key_path: Some(self.dir.join("broker.key").to_string_lossy().to_string()),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+impl Drop for TestCertificates {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// =============================================================================
+
+/// Next packet received from the broker
+async fn next_packet(eventloop: &mut EventLoop) -> Packet {
+    loop {
+        let event = tokio::time::timeout(RECEIVE_TIMEOUT, eventloop.poll())
+            .await
+            .expect("no packet from the broker")
+            .unwrap();
+        if let Event::Incoming(packet) = event {
+            return packet;
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// A client trusting the test authority connects to the TLS listener and gets
+/// its own messages back
+#[tokio::test(flavor = "multi_thread")]
+async fn tls_listener_accepts_trusted_clients() {
+    let certificates = TestCertificates::generate("tls");
+    let mut config = MqttBrokerConfig::new_ephemeral();
+    config.tls = Some(certificates.endpoint());
+    let broker = start_broker(config).await.unwrap();
+    let port = broker.addrs().tls.unwrap().port();
+
+    let mut options = MqttOptions::new("test-tls", "localhost", port);
+    options.set_transport(Transport::tls(
+        certificates.ca_pem.clone().into_bytes(),
+        None,
+        None,
+    ));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    assert!(matches!(
+        next_packet(&mut eventloop).await,
+        Packet::ConnAck(_)
+    ));
+
+    client
+        .subscribe("test/tls", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    client
+        .publish("test/tls", QoS::AtLeastOnce, false, "secure")
+        .await
+        .unwrap();
+    loop {
+        if let Packet::Publish(publish) = next_packet(&mut eventloop).await {
+            assert_eq!(publish.topic, "test/tls");
+            assert_eq!(publish.payload, "secure");
+            break;
+        }
+    }
+
+    broker.shutdown().await.unwrap();
+}